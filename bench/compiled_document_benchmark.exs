@@ -0,0 +1,82 @@
+#!/usr/bin/env mix run
+
+# Per-call latency of frame/query_nodes/project on a JSON string versus a
+# document compiled once with compile_document/2. The string path parses (and
+# for project, expands and indexes) the document on every call.
+
+defmodule CompiledDocumentBenchmark do
+  alias JsonldEx.Native
+
+  @iterations 50
+
+  def run do
+    IO.puts("=" <> String.duplicate("=", 60))
+    IO.puts("Compiled Document Benchmark")
+    IO.puts("=" <> String.duplicate("=", 60))
+
+    frame = Jason.encode!(%{"@context" => %{}, "@graph" => %{}})
+    pattern = Jason.encode!(%{"@type" => "schema:Person"})
+    projection = Jason.encode!(%{"properties" => ["@id", "schema:name"], "type" => "schema:Person"})
+
+    for nodes <- [1_000, 10_000, 50_000] do
+      doc = large_document(nodes)
+      {compile_time, {:ok, compiled}} = :timer.tc(fn -> Native.compile_document(doc, []) end)
+
+      IO.puts("\n#{nodes} nodes (#{Float.round(byte_size(doc) / 1_000_000, 1)} MB), compiled in #{format_time(compile_time)}")
+
+      for {name, call} <- [
+            {"frame", &Native.frame(&1, frame, [])},
+            {"query_nodes", &Native.query_nodes(&1, pattern, [])},
+            {"project", &Native.project(&1, projection, [])}
+          ] do
+        string_p50 = p50(fn -> call.(doc) end)
+        compiled_p50 = p50(fn -> call.(compiled) end)
+        IO.puts("  #{String.pad_trailing(name, 12)} string p50: #{format_time(string_p50)}  compiled p50: #{format_time(compiled_p50)}")
+      end
+    end
+
+    IO.puts("\n" <> "=" <> String.duplicate("=", 60))
+  end
+
+  defp p50(fun) do
+    # Warm up
+    {:ok, _} = fun.()
+
+    times =
+      for _ <- 1..@iterations do
+        {time, {:ok, _}} = :timer.tc(fun)
+        time
+      end
+      |> Enum.sort()
+
+    Enum.at(times, div(length(times), 2))
+  end
+
+  defp large_document(nodes) do
+    graph =
+      for i <- 1..nodes do
+        %{
+          "@id" => "http://example.org/person/#{i}",
+          "@type" => "schema:Person",
+          "schema:name" => "person number #{i}",
+          "schema:knows" => %{"@id" => "http://example.org/person/#{rem(i, nodes) + 1}"}
+        }
+      end
+
+    Jason.encode!(%{"@context" => %{"schema" => "http://schema.org/"}, "@graph" => graph})
+  end
+
+  defp format_time(microseconds) when microseconds < 1_000 do
+    "#{microseconds}μs"
+  end
+
+  defp format_time(microseconds) when microseconds < 1_000_000 do
+    "#{Float.round(microseconds / 1_000, 1)}ms"
+  end
+
+  defp format_time(microseconds) do
+    "#{Float.round(microseconds / 1_000_000, 2)}s"
+  end
+end
+
+CompiledDocumentBenchmark.run()
@@ -0,0 +1,85 @@
+#!/usr/bin/env mix run
+
+# Cost of a single-field edit on a large document: expand_incremental/3 on an
+# open session versus compiling the patched document again. The patch is
+# computed once up front; both sides start from the same session state.
+
+defmodule IncrementalExpansionBenchmark do
+  alias JsonldEx.Native
+
+  @iterations 20
+
+  def run do
+    IO.puts("=" <> String.duplicate("=", 60))
+    IO.puts("Incremental Expansion Benchmark")
+    IO.puts("=" <> String.duplicate("=", 60))
+
+    for nodes <- [1_000, 10_000, 65_000] do
+      doc = large_document(nodes)
+      {:ok, session} = Native.compile_document(doc, [])
+
+      edited = Jason.decode!(doc) |> put_in(["@graph", Access.at(div(nodes, 2)), "schema:name"], "renamed")
+      {:ok, patch} = Native.diff_structural(doc, Jason.encode!(edited), [])
+      {:ok, reverse} = Native.diff_structural(Jason.encode!(edited), doc, [])
+      {:ok, patched} = Native.patch_structural(doc, patch, [])
+
+      IO.puts("\n#{nodes} nodes (#{Float.round(byte_size(doc) / 1_000_000, 1)} MB)")
+
+      # Alternate the edit and its reverse so every call changes the document
+      incremental_p50 =
+        p50(fn i ->
+          {:ok, stats} = Native.expand_incremental(session, if(rem(i, 2) == 0, do: patch, else: reverse), [])
+          "incremental" = Jason.decode!(stats)["strategy"]
+        end)
+
+      full_p50 = p50(fn _ -> {:ok, _} = Native.compile_document(patched, []) end)
+
+      IO.puts("  expand_incremental p50: #{format_time(incremental_p50)}  full re-expansion p50: #{format_time(full_p50)}")
+    end
+
+    IO.puts("\n" <> "=" <> String.duplicate("=", 60))
+  end
+
+  defp p50(fun) do
+    # Warm up
+    fun.(-1)
+    fun.(0)
+
+    times =
+      for i <- 1..@iterations do
+        {time, _} = :timer.tc(fn -> fun.(i) end)
+        time
+      end
+      |> Enum.sort()
+
+    Enum.at(times, div(length(times), 2))
+  end
+
+  defp large_document(nodes) do
+    graph =
+      for i <- 1..nodes do
+        %{
+          "@id" => "http://example.org/person/#{i}",
+          "@type" => "schema:Person",
+          "schema:name" => "person number #{i}",
+          "schema:knows" => %{"@id" => "http://example.org/person/#{rem(i, nodes) + 1}"}
+        }
+      end
+
+    Jason.encode!(%{"@context" => %{"schema" => "http://schema.org/"}, "@graph" => graph})
+  end
+
+  defp format_time(microseconds) when microseconds < 1_000 do
+    "#{microseconds}μs"
+  end
+
+  defp format_time(microseconds) when microseconds < 1_000_000 do
+    "#{Float.round(microseconds / 1_000, 1)}ms"
+  end
+
+  defp format_time(microseconds) do
+    "#{Float.round(microseconds / 1_000_000, 2)}s"
+  end
+end
+
+IncrementalExpansionBenchmark.run()
@@ -0,0 +1,83 @@
+#!/usr/bin/env mix run
+
+# Structural patch latency on a large document receiving a tiny patch.
+# Patching works in place, so cost should track the patch size rather than
+# the document size.
+#
+# Allocation counts need a NIF built with the alloc_stats feature:
+#   JSONLD_NIF_FORCE_BUILD=1 JSONLD_NIF_FEATURES=alloc_stats mix run bench/patch_benchmark.exs
+
+defmodule PatchBenchmark do
+  alias JsonldEx.Native
+
+  @iterations 50
+
+  def run do
+    IO.puts("=" <> String.duplicate("=", 60))
+    IO.puts("Structural Patch Benchmark")
+    IO.puts("=" <> String.duplicate("=", 60))
+
+    for nodes <- [1_000, 50_000, 200_000] do
+      doc = large_document(nodes)
+      patch = Jason.encode!(%{"meta" => %{"version" => [1, 2]}})
+
+      # Warm up
+      {:ok, _} = Native.patch_structural(doc, patch, [])
+
+      times =
+        for _ <- 1..@iterations do
+          {time, {:ok, _}} = :timer.tc(fn -> Native.patch_structural(doc, patch, []) end)
+          time
+        end
+        |> Enum.sort()
+
+      IO.puts("\n#{nodes} nodes (#{Float.round(byte_size(doc) / 1_000_000, 1)} MB)")
+      IO.puts("  p50: #{format_time(percentile(times, 0.5))}")
+      IO.puts("  p99: #{format_time(percentile(times, 0.99))}")
+      report_allocations(doc, patch)
+    end
+
+    IO.puts("\n" <> "=" <> String.duplicate("=", 60))
+  end
+
+  # One patch between two reads of the allocation counters
+  defp report_allocations(doc, patch) do
+    with {:ok, _} <- Native.allocation_stats(),
+         {:ok, _} <- Native.patch_structural(doc, patch, []),
+         {:ok, json} <- Native.allocation_stats() do
+      stats = Jason.decode!(json)
+      IO.puts("  allocations: #{stats["allocations"]}")
+      IO.puts("  allocated: #{format_bytes(stats["bytes_allocated"])}, peak: #{format_bytes(stats["peak_bytes"])}")
+    else
+      {:error, _} -> IO.puts("  allocations: not counted (build with the alloc_stats feature)")
+    end
+  end
+
+  defp format_bytes(bytes) when bytes < 1_000_000, do: "#{Float.round(bytes / 1_000, 1)} KB"
+  defp format_bytes(bytes), do: "#{Float.round(bytes / 1_000_000, 1)} MB"
+
+  defp large_document(nodes) do
+    items =
+      for i <- 1..nodes do
+        %{"@id" => "http://example.org/node/#{i}", "name" => "node number #{i}", "tags" => ["a", "b", "c"]}
+      end
+
+    Jason.encode!(%{"items" => items, "meta" => %{"version" => 1}})
+  end
+
+  defp percentile(sorted, p), do: Enum.at(sorted, min(length(sorted) - 1, trunc(length(sorted) * p)))
+
+  defp format_time(microseconds) when microseconds < 1_000 do
+    "#{microseconds}μs"
+  end
+
+  defp format_time(microseconds) when microseconds < 1_000_000 do
+    "#{Float.round(microseconds / 1_000, 1)}ms"
+  end
+
+  defp format_time(microseconds) do
+    "#{Float.round(microseconds / 1_000_000, 2)}s"
+  end
+end
+
+PatchBenchmark.run()
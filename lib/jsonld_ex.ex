@@ -78,6 +78,13 @@ defmodule JsonldEx do
     end
   end
   
+  defp decode_result({:ok, result, metadata}) when is_binary(result) and is_binary(metadata) do
+    with {:ok, decoded} <- Jason.decode(result),
+         {:ok, decoded_metadata} <- Jason.decode(metadata) do
+      {:ok, decoded, decoded_metadata}
+    end
+  end
+
   defp decode_result(result), do: result
 
   defp decode_binary_result({:ok, result_binary}) when is_binary(result_binary) do
@@ -87,5 +94,7 @@ defmodule JsonldEx do
     end
   end
   
+  defp decode_binary_result({:ok, _result, _metadata} = result), do: decode_result(result)
+
   defp decode_binary_result(result), do: result
 end
@@ -16,15 +16,21 @@ defmodule JsonldEx.C14n do
 
   Options:
   - algorithm: :urdna2015 (default)
+  - bnode_map: true to fill `bnode_map` with each input blank node label and the
+    canonical `_:c14n` label it was issued (default: false, leaving it empty)
   """
   def c14n(term, opts \\ []) do
     :telemetry.execute([:jsonld, :canonicalize, :start], %{}, %{opts: opts})
     alg = Keyword.get(opts, :algorithm, :urdna2015)
     provider = provider()
-    key = {:c14n, provider, alg, stable_key(term)}
+    key = {:c14n, provider, alg, Keyword.get(opts, :bnode_map, false), stable_key(term)}
     with {:miss, nil} <- {:miss, cache_get(key)},
          result <- Perf.normalize_rdf_graph(term, alg, opts) do
       case result do
+        {:ok, nquads, bnode_map} when is_binary(nquads) ->
+          cache_put(key, {nquads, bnode_map})
+          :telemetry.execute([:jsonld, :canonicalize, :stop], %{}, %{algorithm: alg, provider: provider, bytes: byte_size(nquads)})
+          {:ok, %{nquads: nquads, bnode_map: bnode_map}}
         {:ok, nquads} when is_binary(nquads) ->
           cache_put(key, nquads)
           :telemetry.execute([:jsonld, :canonicalize, :stop], %{}, %{algorithm: alg, provider: provider, bytes: byte_size(nquads)})
@@ -37,6 +43,9 @@ defmodule JsonldEx.C14n do
           {:error, :canonicalization_failed}
       end
     else
+      {:miss, {cached, bnode_map}} when is_binary(cached) ->
+        :telemetry.execute([:jsonld, :canonicalize, :stop], %{}, %{algorithm: alg, provider: provider, bytes: byte_size(cached), cache: :hit})
+        {:ok, %{nquads: cached, bnode_map: bnode_map}}
       {:miss, cached} when is_binary(cached) ->
         :telemetry.execute([:jsonld, :canonicalize, :stop], %{}, %{algorithm: alg, provider: provider, bytes: byte_size(cached), cache: :hit})
         {:ok, %{nquads: cached, bnode_map: %{}}}
@@ -0,0 +1,41 @@
+defmodule JsonldEx.Config do
+  @moduledoc """
+  Process-wide default options for the native functions.
+
+  Defaults are read from the application environment when the NIF is loaded:
+
+      config :jsonld_ex, :default_options, strict: true, processing_mode: "json-ld-1.0"
+
+  Every native call starts from these defaults, and any option the call passes
+  itself replaces the default for that key. Keys and values are sent to the NIF
+  as strings, so `strict: true` is the same as `{"strict", "true"}`.
+  """
+
+  alias JsonldEx.Native
+
+  @doc """
+  The `load_data` handed to the NIF on load: the `:default_options` of the
+  `:jsonld_ex` application as string pairs.
+  """
+  def load_data do
+    :jsonld_ex
+    |> Application.get_env(:default_options, [])
+    |> to_native_options()
+  end
+
+  @doc """
+  Replace the process-wide defaults. Calls already running keep the defaults
+  they started with.
+  """
+  def set_default_options(options), do: Native.set_default_options(to_native_options(options))
+
+  @doc """
+  The current defaults as a map of strings.
+  """
+  def get_default_options, do: Native.get_default_options()
+
+  @doc false
+  def to_native_options(options) do
+    Enum.map(options, fn {key, value} -> {to_string(key), to_string(value)} end)
+  end
+end
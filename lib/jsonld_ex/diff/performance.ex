@@ -85,15 +85,60 @@ defmodule JsonldEx.Diff.Performance do
   High-performance LCS computation for arrays.
   
   Falls back to a simplified O(n²) algorithm in Elixir if NIF unavailable.
+
+  With `format: :delta` the result is a jsondiffpatch array delta
+  (`%{"_t" => "a", ...}`) that `Native.patch_structural/3` applies to
+  `old_array`; this form needs the NIF.
   """
   def compute_lcs(old_array, new_array, opts \\ []) do
-    case attempt_native_lcs(old_array, new_array) do
-      {:ok, result} -> {:ok, result}
-      {:error, :nif_not_available} -> compute_lcs_elixir(old_array, new_array, opts)
-      {:error, _reason} -> compute_lcs_elixir(old_array, new_array, opts)
+    if Keyword.get(opts, :format) == :delta do
+      attempt_native_lcs(old_array, new_array, [{"format", "delta"}])
+    else
+      case attempt_native_lcs(old_array, new_array) do
+        {:ok, result} -> {:ok, result}
+        {:error, :nif_not_available} -> compute_lcs_elixir(old_array, new_array, opts)
+        {:error, _reason} -> compute_lcs_elixir(old_array, new_array, opts)
+      end
     end
   end
 
+  @doc """
+  Structural diff of two large arrays as a lazy stream of delta fragments.
+
+  Each fragment is a map of `"_<index>"` entries whose union is the delta
+  `diff_structural/3` would return for the same arrays, computed
+  `:max_items` elements per NIF call (default 10_000) so no single call holds
+  a scheduler for long. Consumers can stop early; the remaining work is never
+  done. Other options are passed to the NIF as `diff_structural/3` takes them.
+  Needs the NIF.
+  """
+  def stream_array_diff(old_array, new_array, opts \\ []) do
+    {max_items, nif_opts} = Keyword.pop(opts, :max_items, 10_000)
+    nif_opts = Enum.map(nif_opts, fn {key, value} -> {to_string(key), to_string(value)} end)
+
+    Stream.resource(
+      fn ->
+        case Native.diff_array_chunked_init(Jason.encode!(old_array), Jason.encode!(new_array), nif_opts) do
+          {:ok, handle} -> {:cont, handle}
+          {:error, reason} -> raise ArgumentError, "cannot diff arrays: #{inspect(reason)}"
+        end
+      end,
+      fn
+        :halt ->
+          {:halt, :halt}
+
+        {:cont, handle} ->
+          case Native.diff_array_chunked_next(handle, max_items) do
+            {:ok, "{}"} -> {[], {:cont, handle}}
+            {:ok, fragment} -> {[Jason.decode!(fragment)], {:cont, handle}}
+            {:done, "{}"} -> {:halt, :halt}
+            {:done, fragment} -> {[Jason.decode!(fragment)], :halt}
+          end
+      end,
+      fn _ -> :ok end
+    )
+  end
+
   @doc """
   High-performance text diff using Myers' algorithm.
   
@@ -109,9 +154,13 @@ defmodule JsonldEx.Diff.Performance do
 
   @doc """
   High-performance RDF graph normalization.
+
+  With `bnode_map: true` the native path returns `{:ok, nquads, bnode_map}`, where
+  `bnode_map` maps each input blank node label to its canonical `_:c14n` label.
   """
   def normalize_rdf_graph(document, algorithm \\ :urdna2015, opts \\ []) do
-    case attempt_native_rdf_normalization(document, algorithm) do
+    case attempt_native_rdf_normalization(document, algorithm, opts) do
+      {:ok, result, bnode_map} -> {:ok, result, bnode_map}
       {:ok, result} -> {:ok, result}
       {:error, :nif_not_available} -> normalize_rdf_elixir(document, algorithm, opts)
       {:error, _reason} -> normalize_rdf_elixir(document, algorithm, opts)
@@ -283,12 +332,12 @@ defmodule JsonldEx.Diff.Performance do
     end
   end
 
-  defp attempt_native_lcs(old_array, new_array) do
+  defp attempt_native_lcs(old_array, new_array, nif_opts \\ []) do
     try do
       old_json = Jason.encode!(old_array)
       new_json = Jason.encode!(new_array)
       
-      case Native.compute_lcs_array(old_json, new_json) do
+      case Native.compute_lcs_array(old_json, new_json, nif_opts) do
         {:ok, result_json} -> {:ok, Jason.decode!(result_json)}
         error -> error
       end
@@ -307,10 +356,18 @@ defmodule JsonldEx.Diff.Performance do
     end
   end
 
-  defp attempt_native_rdf_normalization(document, algorithm) do
+  defp attempt_native_rdf_normalization(document, algorithm, opts) do
     try do
       doc_json = Jason.encode!(document)
-      Native.normalize_rdf_graph(doc_json, algorithm)
+
+      if Keyword.get(opts, :bnode_map, false) do
+        case Native.normalize_rdf_graph(doc_json, to_string(algorithm), [{"bnode_map", "true"}]) do
+          {:ok, nquads, map_json} -> {:ok, nquads, Jason.decode!(map_json)}
+          other -> other
+        end
+      else
+        Native.normalize_rdf_graph(doc_json, to_string(algorithm))
+      end
     rescue
       UndefinedFunctionError -> {:error, :nif_not_available}
       error -> {:error, error}
@@ -153,17 +153,16 @@ defmodule JsonldEx.Diff.Structural do
     
     all_keys
     |> Enum.reduce(%{}, fn key, acc ->
-      old_val = Map.get(old, key)
-      new_val = Map.get(new, key)
-      
-      delta = cond do
-        old_val == nil ->
+      # A key holding null is still present: changing a value to null is a
+      # change, and only a missing key is an addition or a deletion
+      delta = case {Map.fetch(old, key), Map.fetch(new, key)} do
+        {:error, {:ok, new_val}} ->
           [new_val]  # Added
         
-        new_val == nil ->
+        {{:ok, old_val}, :error} ->
           [old_val, 0, 0]  # Deleted
         
-        old_val != new_val ->
+        {{:ok, old_val}, {:ok, new_val}} when old_val != new_val ->
           sub_delta = diff_value(old_val, new_val, opts)
           if is_map(sub_delta) and map_size(sub_delta) == 0 do
             nil
@@ -171,7 +170,7 @@ defmodule JsonldEx.Diff.Structural do
             sub_delta
           end
         
-        true ->
+        _ ->
           nil
       end
       
@@ -201,10 +200,10 @@ defmodule JsonldEx.Diff.Structural do
       new_val = Enum.at(new, index)
       
       delta = cond do
-        old_val == nil and new_val != nil ->
+        index >= length(old) ->
           [new_val]  # Added
         
-        old_val != nil and new_val == nil ->
+        index >= length(new) ->
           [old_val, 0, 0]  # Deleted
         
         old_val != new_val ->
@@ -343,14 +342,13 @@ defmodule JsonldEx.Diff.Structural do
               # Delete key
               Map.delete(acc, key)
             
+            subdelta when is_map(subdelta) and not is_map_key(acc, key) ->
+              # Nested delta for a key the document doesn't have
+              acc
+            
             _ ->
-              # Update or add key
-              new_value = patch_value(current_value, subdelta, opts)
-              if new_value == nil do
-                Map.delete(acc, key)
-              else
-                Map.put(acc, key, new_value)
-              end
+              # Update or add key; a nil result is a value set to null and keeps the key
+              Map.put(acc, key, patch_value(current_value, subdelta, opts))
           end
       end
     end)
@@ -24,6 +24,7 @@ defmodule JsonldEx.Native do
       base_url: "https://github.com/zpc-sh/jsonld_ex/releases/download/v#{@version}",
       force_build: System.get_env("JSONLD_NIF_FORCE_BUILD") in ["1", "true"],
       features: @nif_features,
+      load_data_fun: {JsonldEx.Config, :load_data},
       # Temporarily reduced target set to match current release matrix.
       # Unsupported hosts fall back to local build automatically.
       targets: [
@@ -40,34 +41,75 @@ defmodule JsonldEx.Native do
 
   def expand(_input, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def expand_binary(_input, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def expand_nodes(_input, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def compact(_input, _context, _opts), do: :erlang.nif_error(:nif_not_loaded) 
   def flatten(_input, _context, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def to_rdf(_input, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def from_rdf(_input, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def jsonld_to_cbor_ld(_document, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def cbor_ld_to_jsonld(_input, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def frame(_input, _frame, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def processor_new(_context, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def processor_expand(_processor, _input), do: :erlang.nif_error(:nif_not_loaded)
+  def processor_compact(_processor, _input), do: :erlang.nif_error(:nif_not_loaded)
+  def document_open(_document), do: :erlang.nif_error(:nif_not_loaded)
+  def compile_document(_document, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def expand_incremental(_document, _patch, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def document_expanded(_document), do: :erlang.nif_error(:nif_not_loaded)
+  def project(_document, _projection, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def incoming_references(_document, _node_id), do: :erlang.nif_error(:nif_not_loaded)
+  def context_coverage(_document, _context), do: :erlang.nif_error(:nif_not_loaded)
+  def redact(_document, _properties, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def filter_language(_document, _languages, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def parse_semantic_version(_version), do: :erlang.nif_error(:nif_not_loaded)
   def compare_versions(_version1, _version2), do: :erlang.nif_error(:nif_not_loaded)
   def satisfies_requirement(_version, _requirement), do: :erlang.nif_error(:nif_not_loaded)
-  def query_nodes(_document, _pattern), do: :erlang.nif_error(:nif_not_loaded)
+  def query_nodes(document, pattern), do: query_nodes(document, pattern, [])
+  def query_nodes(_document, _pattern, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def normalize_iri(_iri, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def document_stats(_input), do: :erlang.nif_error(:nif_not_loaded)
+  def graph_stats(_document), do: :erlang.nif_error(:nif_not_loaded)
+  def find_similar_nodes(_document, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def cache_context(_context, _key), do: :erlang.nif_error(:nif_not_loaded)
+  def cache_context(_context, _key, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def deref_context(_document, _contexts), do: :erlang.nif_error(:nif_not_loaded)
+  def context_cache_stats(_opts), do: :erlang.nif_error(:nif_not_loaded)
+  def processing_stats(), do: :erlang.nif_error(:nif_not_loaded)
+  def allocation_stats(), do: :erlang.nif_error(:nif_not_loaded)
+  def set_default_options(_options), do: :erlang.nif_error(:nif_not_loaded)
+  def get_default_options(), do: :erlang.nif_error(:nif_not_loaded)
+  def recent_incidents(), do: :erlang.nif_error(:nif_not_loaded)
+  def clear_incidents(), do: :erlang.nif_error(:nif_not_loaded)
+  def clear_caches(_opts), do: :erlang.nif_error(:nif_not_loaded)
+  def export_caches(), do: :erlang.nif_error(:nif_not_loaded)
+  def import_caches(_snapshot), do: :erlang.nif_error(:nif_not_loaded)
   def batch_process(_operations), do: :erlang.nif_error(:nif_not_loaded)
   def batch_expand(_documents), do: :erlang.nif_error(:nif_not_loaded)
   def validate_document(_document, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def optimize_for_storage(_document), do: :erlang.nif_error(:nif_not_loaded)
   def detect_cycles(_graph), do: :erlang.nif_error(:nif_not_loaded)
+  def dependency_graph_dot(_graph, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def generate_blueprint_context(_blueprint_data, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def merge_documents(_documents, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def build_dependency_graph(_blueprints), do: :erlang.nif_error(:nif_not_loaded)
   
   # High-performance diff operations
   def diff_structural(_old_document, _new_document, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def diff_array_chunked_init(_old_array, _new_array, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def diff_array_chunked_next(_handle, _max_items), do: :erlang.nif_error(:nif_not_loaded)
   def diff_operational(_old_document, _new_document, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def hash_document(_document), do: :erlang.nif_error(:nif_not_loaded)
   def diff_semantic(_old_document, _new_document, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def patch_structural(_document, _patch, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def patch_operational(_document, _patch, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def patch_semantic(_document, _patch, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def merge_diffs_operational(_diffs, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def convert_patch(_patch, _from_format, _to_format, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def normalize_rdf_graph(_document, _algorithm), do: :erlang.nif_error(:nif_not_loaded)
+  def normalize_rdf_graph(_document, _algorithm, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def canonicalize_nquads(_nquads, _opts), do: :erlang.nif_error(:nif_not_loaded)
+  def normalize_document(_document, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def compute_lcs_array(_old_array, _new_array), do: :erlang.nif_error(:nif_not_loaded)
+  def compute_lcs_array(_old_array, _new_array, _opts), do: :erlang.nif_error(:nif_not_loaded)
   def text_diff_myers(_old_text, _new_text), do: :erlang.nif_error(:nif_not_loaded)
 end
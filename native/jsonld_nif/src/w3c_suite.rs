@@ -0,0 +1,409 @@
+// W3C JSON-LD 1.1 conformance harness (run with `cargo test w3c`).
+//
+// Reads the expand, compact, flatten and toRdf manifests from tests/w3c, or from
+// a json-ld-api/tests checkout named by JSONLD_TEST_SUITE, and runs every case
+// through the same functions the NIFs call. Vendored cases listed in
+// known_failures.txt are reported but don't fail the run; a checkout has no
+// such list, so its run only reports. A per-suite summary is printed and, with
+// JSONLD_W3C_REPORT=<path>, written as JSON.
+//
+// JSONLD_W3C_RECORD=1 rewrites known_failures.txt from the current run.
+//
+// Remote contexts are never fetched: files under the suite root are registered
+// in CONTEXT_CACHE under their test-suite URL and dereferenced from there.
+
+use super::*;
+use std::collections::{BTreeSet, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+const SUITE_BASE: &str = "https://w3c.github.io/json-ld-api/tests/";
+
+const SUITES: [(&str, &str); 4] = [
+    ("expand", "expand-manifest.jsonld"),
+    ("compact", "compact-manifest.jsonld"),
+    ("flatten", "flatten-manifest.jsonld"),
+    ("toRdf", "toRdf-manifest.jsonld"),
+];
+
+// Options this processor has no equivalent for; cases using them are skipped
+const UNSUPPORTED_OPTIONS: [&str; 5] = ["compactArrays", "extractAllScripts", "produceGeneralizedRdf", "rdfDirection", "compactToRelative"];
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip,
+}
+
+#[derive(Default)]
+struct SuiteCounts {
+    passed: usize,
+    failed: usize,
+    known_failures: usize,
+    skipped: usize,
+}
+
+struct SuiteLocation {
+    root: PathBuf,
+    // None for a suite checkout, whose failures are reported rather than asserted
+    known_failures: Option<PathBuf>,
+}
+
+fn suite_location() -> SuiteLocation {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/w3c");
+    match std::env::var_os("JSONLD_TEST_SUITE") {
+        Some(root) => SuiteLocation { root: PathBuf::from(root), known_failures: None },
+        None => SuiteLocation { known_failures: Some(fixtures.join("known_failures.txt")), root: fixtures },
+    }
+}
+
+fn read_json(path: &Path) -> Result<Value, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn read_known_failures(path: &Path) -> BTreeSet<String> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+fn write_known_failures(path: &Path, failures: &BTreeSet<String>) {
+    let mut out = String::from("# W3C cases expected to fail; regenerate with JSONLD_W3C_RECORD=1 cargo test w3c\n");
+    for key in failures {
+        out.push_str(key);
+        out.push('\n');
+    }
+    std::fs::write(path, out).expect("write known failures");
+}
+
+// Registers every string @context reachable from `document` that names a file
+// under the suite root, following contexts that include further contexts
+fn register_remote_contexts(document: &Value, document_url: &str, root: &Path, seen: &mut HashSet<String>) {
+    let mut references = Vec::new();
+    walk_json(document, |value, _| {
+        if let Some(context) = value.as_object().and_then(|obj| obj.get("@context")) {
+            match context {
+                Value::String(reference) => references.push(reference.clone()),
+                Value::Array(entries) => references.extend(entries.iter().filter_map(|e| e.as_str()).map(str::to_string)),
+                _ => {}
+            }
+        }
+    });
+
+    for reference in references {
+        let url = resolve_context_reference(&reference, Some(document_url));
+        let Some(relative) = url.strip_prefix(SUITE_BASE) else { continue };
+        if !seen.insert(url.clone()) {
+            continue;
+        }
+        let Ok(text) = std::fs::read_to_string(root.join(relative)) else { continue };
+        if let Ok(remote) = serde_json::from_str::<Value>(&text) {
+            register_remote_contexts(&remote, &url, root, seen);
+        }
+        CONTEXT_CACHE.lock().unwrap().put((DEFAULT_CACHE_NAMESPACE.to_string(), url), Arc::new(text));
+    }
+}
+
+fn run_case(suite: &str, root: &Path, case: &serde_json::Map<String, Value>) -> Outcome {
+    let types: Vec<&str> = match case.get("@type") {
+        Some(Value::Array(types)) => types.iter().filter_map(|t| t.as_str()).collect(),
+        Some(Value::String(t)) => vec![t.as_str()],
+        _ => Vec::new(),
+    };
+    let options = case.get("option").and_then(|o| o.as_object()).cloned().unwrap_or_default();
+
+    if options.get("specVersion").and_then(|v| v.as_str()) == Some("json-ld-1.0") {
+        return Outcome::Skip;
+    }
+    if options.get("processingMode").and_then(|v| v.as_str()) == Some("json-ld-1.0") {
+        return Outcome::Skip;
+    }
+    if UNSUPPORTED_OPTIONS.iter().any(|option| options.contains_key(*option)) {
+        return Outcome::Skip;
+    }
+
+    let Some(input_path) = case.get("input").and_then(|i| i.as_str()) else {
+        return Outcome::Fail("case has no input".to_string());
+    };
+    if !input_path.ends_with(".jsonld") && !input_path.ends_with(".json") {
+        return Outcome::Skip;
+    }
+    let input = match read_json(&root.join(input_path)) {
+        Ok(input) => input,
+        Err(e) => return Outcome::Fail(e),
+    };
+    let base = options
+        .get("base")
+        .and_then(|b| b.as_str())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{}{}", SUITE_BASE, input_path));
+    register_remote_contexts(&input, &base, root, &mut HashSet::new());
+
+    let context = match case.get("context").and_then(|c| c.as_str()) {
+        Some(path) => match read_json(&root.join(path)) {
+            Ok(context) => Some(context),
+            Err(e) => return Outcome::Fail(e),
+        },
+        None => None,
+    };
+
+    // The suite expects spec errors, which lenient mode only reports as warnings
+    let negative = types.contains(&"jld:NegativeEvaluationTest");
+    let result = match suite {
+        "expand" => expand_case(input, &base, &options, root, negative),
+        "compact" => simple_compact(input, context.unwrap_or(Value::Null), &CompactOptions::iri_compaction()),
+        "flatten" => flatten_case(input, context),
+        "toRdf" => document_to_quads(input).map(|(quads, _)| Value::String(quads_to_nquads(&quads))),
+        _ => return Outcome::Skip,
+    };
+
+    if negative {
+        let expected = case.get("expectErrorCode").and_then(|c| c.as_str()).unwrap_or_default();
+        return match result {
+            Err(e) if error_code_matches(e.code, expected) => Outcome::Pass,
+            Err(e) => Outcome::Fail(format!("expected {:?}, got {} ({})", expected, e.code, e.message)),
+            Ok(output) => Outcome::Fail(format!("expected {:?}, got {}", expected, output)),
+        };
+    }
+
+    let output = match result {
+        Ok(output) => output,
+        Err(e) => return Outcome::Fail(format!("{}: {}", e.code, e.message)),
+    };
+    if types.contains(&"jld:PositiveSyntaxTest") {
+        return Outcome::Pass;
+    }
+
+    let Some(expect_path) = case.get("expect").and_then(|e| e.as_str()) else {
+        return Outcome::Fail("case has no expect".to_string());
+    };
+    if suite == "toRdf" {
+        let expected = match std::fs::read_to_string(root.join(expect_path)) {
+            Ok(expected) => expected,
+            Err(e) => return Outcome::Fail(e.to_string()),
+        };
+        let actual = output.as_str().unwrap_or_default();
+        return if nquad_set(actual) == nquad_set(&expected) {
+            Outcome::Pass
+        } else {
+            Outcome::Fail(format!("got\n{}expected\n{}", actual, expected))
+        };
+    }
+
+    let expected = match read_json(&root.join(expect_path)) {
+        Ok(expected) => expected,
+        Err(e) => return Outcome::Fail(e),
+    };
+    let output = if suite == "expand" { spec_expanded_form(output) } else { output };
+    if json_equivalent(&output, &expected) {
+        Outcome::Pass
+    } else {
+        Outcome::Fail(format!("got {} expected {}", output, expected))
+    }
+}
+
+// Expansion always yields an array at the top level; the document URL is the
+// base the NIFs don't otherwise have. Cases run lenient, as the NIFs do by
+// default, except negative ones.
+fn expand_case(input: Value, base: &str, options: &serde_json::Map<String, Value>, root: &Path, strict: bool) -> Result<Value, ExpandError> {
+    let mut state = ExpandState { strict, ..ExpandState::default() };
+    let mut active_context = Context { base: Some(base.to_string()), ..default_context() };
+    if let Some(path) = options.get("expandContext").and_then(|c| c.as_str()) {
+        let context = read_json(&root.join(path)).map_err(|e| ExpandError::new("loading_document_failed", e))?;
+        active_context = process_context(&active_context, &unwrap_local_context(context), &mut state)?;
+    }
+    Ok(match expand_document_with_context(input, &active_context, &mut state)? {
+        Value::Array(arr) => Value::Array(arr),
+        Value::Null => json!([]),
+        other => json!([other]),
+    })
+}
+
+// flatten/3 only attaches a context; the spec's flatten compacts against it,
+// as a caller would with compact/3 on the flattened graph
+fn flatten_case(input: Value, context: Option<Value>) -> Result<Value, ExpandError> {
+    let mut issuer = BlankNodeIssuer::new("_:b");
+    let mut flattened = flatten_document(input, None, &mut issuer, &mut ExpandState::default())?;
+    let graph = flattened["@graph"].take();
+    match context {
+        Some(context) => simple_compact(graph, context, &CompactOptions::iri_compaction()),
+        None => Ok(graph),
+    }
+}
+
+// The processor's expanded form leaves a single property value unwrapped and
+// gives native numbers and booleans their datatype; both are undone so the
+// output compares with the suite's
+fn spec_expanded_form(value: Value) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(items.into_iter().map(spec_expanded_form).collect()),
+        Value::Object(mut literal) if literal.contains_key("@value") => {
+            let native_type = match &literal["@value"] {
+                Value::Bool(_) => Some(XSD_BOOLEAN.to_string()),
+                Value::Number(n) => Some(NumberDatatypes::default().datatype_for(n).to_string()),
+                _ => None,
+            };
+            if native_type.is_some() && literal.get("@type").and_then(Value::as_str) == native_type.as_deref() {
+                literal.remove("@type");
+            }
+            Value::Object(literal)
+        }
+        Value::Object(node) => Value::Object(
+            node.into_iter()
+                .map(|(key, value)| {
+                    let value = match key.as_str() {
+                        "@id" | "@index" | "@language" | "@direction" | "@context" => value,
+                        "@reverse" => match value {
+                            Value::Object(reverse) => Value::Object(reverse.into_iter().map(|(k, v)| (k, as_array(spec_expanded_form(v)))).collect()),
+                            other => other,
+                        },
+                        _ => as_array(spec_expanded_form(value)),
+                    };
+                    (key, value)
+                })
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+fn as_array(value: Value) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(items),
+        other => Value::Array(vec![other]),
+    }
+}
+
+// Suite codes are phrases ("invalid @id value"), ours are snake_case atoms
+fn error_code_matches(actual: &str, expected: &str) -> bool {
+    let normalize = |code: &str| {
+        code.split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_ascii_lowercase)
+            .collect::<Vec<_>>()
+            .join("_")
+    };
+    normalize(actual) == normalize(expected)
+}
+
+fn nquad_set(nquads: &str) -> BTreeSet<String> {
+    nquads
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+// Arrays compare as multisets except @list contents, which keep their order
+fn json_equivalent(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Object(a), Value::Object(e)) => {
+            a.len() == e.len()
+                && a.iter().all(|(key, value)| match (key.as_str(), value, e.get(key)) {
+                    ("@list", Value::Array(items), Some(Value::Array(expected_items))) => {
+                        items.len() == expected_items.len()
+                            && items.iter().zip(expected_items).all(|(x, y)| json_equivalent(x, y))
+                    }
+                    (_, value, Some(expected_value)) => json_equivalent(value, expected_value),
+                    (_, _, None) => false,
+                })
+        }
+        (Value::Array(a), Value::Array(e)) => {
+            if a.len() != e.len() {
+                return false;
+            }
+            let mut unmatched: Vec<&Value> = e.iter().collect();
+            a.iter().all(|item| match unmatched.iter().position(|candidate| json_equivalent(item, candidate)) {
+                Some(pos) => {
+                    unmatched.swap_remove(pos);
+                    true
+                }
+                None => false,
+            })
+        }
+        (Value::Number(a), Value::Number(e)) => a.as_f64() == e.as_f64(),
+        _ => actual == expected,
+    }
+}
+
+#[test]
+fn w3c_manifests() {
+    let location = suite_location();
+    let known = location.known_failures.as_deref().map(read_known_failures).unwrap_or_default();
+    let recording = std::env::var_os("JSONLD_W3C_RECORD").is_some();
+
+    let mut failing = BTreeSet::new();
+    let mut unexpected = Vec::new();
+    let mut now_passing = Vec::new();
+    let mut report = serde_json::Map::new();
+    let mut summary = format!("W3C JSON-LD conformance ({})\n{:<10}{:>8}{:>8}{:>8}{:>8}\n", location.root.display(), "suite", "pass", "fail", "known", "skip");
+
+    for (suite, manifest_name) in SUITES {
+        let manifest_path = location.root.join(manifest_name);
+        if !manifest_path.exists() {
+            continue;
+        }
+        let manifest = read_json(&manifest_path).expect("read manifest");
+        let cases = manifest["sequence"].as_array().cloned().unwrap_or_default();
+        let mut counts = SuiteCounts::default();
+
+        for case in cases.iter().filter_map(|c| c.as_object()) {
+            let key = format!("{}{}", manifest_name, case.get("@id").and_then(|id| id.as_str()).unwrap_or_default());
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| run_case(suite, &location.root, case)))
+                .unwrap_or_else(|_| Outcome::Fail("panicked".to_string()));
+
+            match outcome {
+                Outcome::Pass if known.contains(&key) => {
+                    counts.passed += 1;
+                    now_passing.push(key);
+                }
+                Outcome::Pass => counts.passed += 1,
+                Outcome::Skip => counts.skipped += 1,
+                Outcome::Fail(_) if known.contains(&key) => {
+                    counts.known_failures += 1;
+                    failing.insert(key);
+                }
+                Outcome::Fail(reason) => {
+                    counts.failed += 1;
+                    unexpected.push(format!("{}: {}", key, reason));
+                    failing.insert(key);
+                }
+            }
+        }
+
+        summary.push_str(&format!("{:<10}{:>8}{:>8}{:>8}{:>8}\n", suite, counts.passed, counts.failed, counts.known_failures, counts.skipped));
+        report.insert(
+            suite.to_string(),
+            json!({
+                "passed": counts.passed,
+                "failed": counts.failed,
+                "known_failures": counts.known_failures,
+                "skipped": counts.skipped
+            }),
+        );
+    }
+
+    println!("{}", summary);
+    if let Some(path) = std::env::var_os("JSONLD_W3C_REPORT") {
+        std::fs::write(path, serde_json::to_string_pretty(&Value::Object(report)).unwrap()).expect("write report");
+    }
+
+    let Some(known_failures) = location.known_failures else {
+        println!("{} failing cases:\n{}", unexpected.len(), unexpected.join("\n"));
+        return;
+    };
+    if !now_passing.is_empty() {
+        println!("now passing, remove from {}:\n  {}", known_failures.display(), now_passing.join("\n  "));
+    }
+    if recording {
+        write_known_failures(&known_failures, &failing);
+        return;
+    }
+    assert!(unexpected.is_empty(), "{} unexpected W3C failures:\n{}", unexpected.len(), unexpected.join("\n"));
+}
@@ -0,0 +1,187 @@
+/* The enif_* functions the NIF imports from the BEAM when it is loaded. The
+ * W3C harness (src/w3c_suite.rs) runs as a plain `cargo test` binary, which
+ * has to resolve them to link but never calls them; each stub traps if it is
+ * reached. The list matches the symbols rustler 0.34 declares.
+ *
+ *   cc -c tests/enif_stubs.c -o "$DIR/enif_stubs.o" && ar rcs "$DIR/libenif_stubs.a" "$DIR/enif_stubs.o"
+ *   RUSTFLAGS="-L $DIR -l static=enif_stubs" cargo test w3c
+ */
+void enif_alloc(void){__builtin_trap();}
+void enif_alloc_binary(void){__builtin_trap();}
+void enif_alloc_env(void){__builtin_trap();}
+void enif_alloc_resource(void){__builtin_trap();}
+void enif_binary_to_term(void){__builtin_trap();}
+void enif_clear_env(void){__builtin_trap();}
+void enif_compare(void){__builtin_trap();}
+void enif_compare_monitors(void){__builtin_trap();}
+void enif_compare_pids(void){__builtin_trap();}
+void enif_cond_broadcast(void){__builtin_trap();}
+void enif_cond_create(void){__builtin_trap();}
+void enif_cond_destroy(void){__builtin_trap();}
+void enif_cond_name(void){__builtin_trap();}
+void enif_cond_signal(void){__builtin_trap();}
+void enif_cond_wait(void){__builtin_trap();}
+void enif_consume_timeslice(void){__builtin_trap();}
+void enif_convert_time_unit(void){__builtin_trap();}
+void enif_cpu_time(void){__builtin_trap();}
+void enif_demonitor_process(void){__builtin_trap();}
+void enif_dlopen(void){__builtin_trap();}
+void enif_dlsym(void){__builtin_trap();}
+void enif_dynamic_resource_call(void){__builtin_trap();}
+void enif_equal_tids(void){__builtin_trap();}
+void enif_fprintf(void){__builtin_trap();}
+void enif_free(void){__builtin_trap();}
+void enif_free_env(void){__builtin_trap();}
+void enif_free_iovec(void){__builtin_trap();}
+void enif_get_atom(void){__builtin_trap();}
+void enif_get_atom_length(void){__builtin_trap();}
+void enif_get_double(void){__builtin_trap();}
+void enif_get_int(void){__builtin_trap();}
+void enif_get_int64(void){__builtin_trap();}
+void enif_get_list_cell(void){__builtin_trap();}
+void enif_get_list_length(void){__builtin_trap();}
+void enif_get_local_pid(void){__builtin_trap();}
+void enif_get_local_port(void){__builtin_trap();}
+void enif_get_long(void){__builtin_trap();}
+void enif_get_map_size(void){__builtin_trap();}
+void enif_get_map_value(void){__builtin_trap();}
+void enif_get_resource(void){__builtin_trap();}
+void enif_get_string(void){__builtin_trap();}
+void enif_get_string_length(void){__builtin_trap();}
+void enif_get_tuple(void){__builtin_trap();}
+void enif_get_uint(void){__builtin_trap();}
+void enif_get_uint64(void){__builtin_trap();}
+void enif_get_ulong(void){__builtin_trap();}
+void enif_getenv(void){__builtin_trap();}
+void enif_has_pending_exception(void){__builtin_trap();}
+void enif_hash(void){__builtin_trap();}
+void enif_init_resource_type(void){__builtin_trap();}
+void enif_inspect_binary(void){__builtin_trap();}
+void enif_inspect_iolist_as_binary(void){__builtin_trap();}
+void enif_inspect_iovec(void){__builtin_trap();}
+void enif_ioq_create(void){__builtin_trap();}
+void enif_ioq_deq(void){__builtin_trap();}
+void enif_ioq_destroy(void){__builtin_trap();}
+void enif_ioq_enq_binary(void){__builtin_trap();}
+void enif_ioq_enqv(void){__builtin_trap();}
+void enif_ioq_peek(void){__builtin_trap();}
+void enif_ioq_peek_head(void){__builtin_trap();}
+void enif_ioq_size(void){__builtin_trap();}
+void enif_is_atom(void){__builtin_trap();}
+void enif_is_binary(void){__builtin_trap();}
+void enif_is_current_process_alive(void){__builtin_trap();}
+void enif_is_empty_list(void){__builtin_trap();}
+void enif_is_exception(void){__builtin_trap();}
+void enif_is_fun(void){__builtin_trap();}
+void enif_is_identical(void){__builtin_trap();}
+void enif_is_list(void){__builtin_trap();}
+void enif_is_map(void){__builtin_trap();}
+void enif_is_number(void){__builtin_trap();}
+void enif_is_pid(void){__builtin_trap();}
+void enif_is_pid_undefined(void){__builtin_trap();}
+void enif_is_port(void){__builtin_trap();}
+void enif_is_port_alive(void){__builtin_trap();}
+void enif_is_process_alive(void){__builtin_trap();}
+void enif_is_ref(void){__builtin_trap();}
+void enif_is_tuple(void){__builtin_trap();}
+void enif_keep_resource(void){__builtin_trap();}
+void enif_make_atom(void){__builtin_trap();}
+void enif_make_atom_len(void){__builtin_trap();}
+void enif_make_badarg(void){__builtin_trap();}
+void enif_make_binary(void){__builtin_trap();}
+void enif_make_copy(void){__builtin_trap();}
+void enif_make_double(void){__builtin_trap();}
+void enif_make_existing_atom(void){__builtin_trap();}
+void enif_make_existing_atom_len(void){__builtin_trap();}
+void enif_make_int(void){__builtin_trap();}
+void enif_make_int64(void){__builtin_trap();}
+void enif_make_list(void){__builtin_trap();}
+void enif_make_list_cell(void){__builtin_trap();}
+void enif_make_list_from_array(void){__builtin_trap();}
+void enif_make_long(void){__builtin_trap();}
+void enif_make_map_from_arrays(void){__builtin_trap();}
+void enif_make_map_put(void){__builtin_trap();}
+void enif_make_map_remove(void){__builtin_trap();}
+void enif_make_map_update(void){__builtin_trap();}
+void enif_make_monitor_term(void){__builtin_trap();}
+void enif_make_new_atom(void){__builtin_trap();}
+void enif_make_new_atom_len(void){__builtin_trap();}
+void enif_make_new_binary(void){__builtin_trap();}
+void enif_make_new_map(void){__builtin_trap();}
+void enif_make_pid(void){__builtin_trap();}
+void enif_make_ref(void){__builtin_trap();}
+void enif_make_resource(void){__builtin_trap();}
+void enif_make_resource_binary(void){__builtin_trap();}
+void enif_make_reverse_list(void){__builtin_trap();}
+void enif_make_string(void){__builtin_trap();}
+void enif_make_string_len(void){__builtin_trap();}
+void enif_make_sub_binary(void){__builtin_trap();}
+void enif_make_tuple(void){__builtin_trap();}
+void enif_make_tuple_from_array(void){__builtin_trap();}
+void enif_make_uint(void){__builtin_trap();}
+void enif_make_uint64(void){__builtin_trap();}
+void enif_make_ulong(void){__builtin_trap();}
+void enif_make_unique_integer(void){__builtin_trap();}
+void enif_map_iterator_create(void){__builtin_trap();}
+void enif_map_iterator_destroy(void){__builtin_trap();}
+void enif_map_iterator_get_pair(void){__builtin_trap();}
+void enif_map_iterator_is_head(void){__builtin_trap();}
+void enif_map_iterator_is_tail(void){__builtin_trap();}
+void enif_map_iterator_next(void){__builtin_trap();}
+void enif_map_iterator_prev(void){__builtin_trap();}
+void enif_monitor_process(void){__builtin_trap();}
+void enif_monotonic_time(void){__builtin_trap();}
+void enif_mutex_create(void){__builtin_trap();}
+void enif_mutex_destroy(void){__builtin_trap();}
+void enif_mutex_lock(void){__builtin_trap();}
+void enif_mutex_name(void){__builtin_trap();}
+void enif_mutex_trylock(void){__builtin_trap();}
+void enif_mutex_unlock(void){__builtin_trap();}
+void enif_now_time(void){__builtin_trap();}
+void enif_open_resource_type(void){__builtin_trap();}
+void enif_open_resource_type_x(void){__builtin_trap();}
+void enif_port_command(void){__builtin_trap();}
+void enif_priv_data(void){__builtin_trap();}
+void enif_raise_exception(void){__builtin_trap();}
+void enif_realloc(void){__builtin_trap();}
+void enif_realloc_binary(void){__builtin_trap();}
+void enif_release_binary(void){__builtin_trap();}
+void enif_release_resource(void){__builtin_trap();}
+void enif_rwlock_create(void){__builtin_trap();}
+void enif_rwlock_destroy(void){__builtin_trap();}
+void enif_rwlock_name(void){__builtin_trap();}
+void enif_rwlock_rlock(void){__builtin_trap();}
+void enif_rwlock_runlock(void){__builtin_trap();}
+void enif_rwlock_rwlock(void){__builtin_trap();}
+void enif_rwlock_rwunlock(void){__builtin_trap();}
+void enif_rwlock_tryrlock(void){__builtin_trap();}
+void enif_rwlock_tryrwlock(void){__builtin_trap();}
+void enif_schedule_nif(void){__builtin_trap();}
+void enif_select(void){__builtin_trap();}
+void enif_select_x(void){__builtin_trap();}
+void enif_self(void){__builtin_trap();}
+void enif_send(void){__builtin_trap();}
+void enif_set_option(void){__builtin_trap();}
+void enif_set_pid_undefined(void){__builtin_trap();}
+void enif_sizeof_resource(void){__builtin_trap();}
+void enif_snprintf(void){__builtin_trap();}
+void enif_system_info(void){__builtin_trap();}
+void enif_term_to_binary(void){__builtin_trap();}
+void enif_term_type(void){__builtin_trap();}
+void enif_thread_create(void){__builtin_trap();}
+void enif_thread_exit(void){__builtin_trap();}
+void enif_thread_join(void){__builtin_trap();}
+void enif_thread_name(void){__builtin_trap();}
+void enif_thread_opts_create(void){__builtin_trap();}
+void enif_thread_opts_destroy(void){__builtin_trap();}
+void enif_thread_self(void){__builtin_trap();}
+void enif_thread_type(void){__builtin_trap();}
+void enif_time_offset(void){__builtin_trap();}
+void enif_tsd_get(void){__builtin_trap();}
+void enif_tsd_key_create(void){__builtin_trap();}
+void enif_tsd_key_destroy(void){__builtin_trap();}
+void enif_tsd_set(void){__builtin_trap();}
+void enif_vfprintf(void){__builtin_trap();}
+void enif_vsnprintf(void){__builtin_trap();}
+void enif_whereis_pid(void){__builtin_trap();}
+void enif_whereis_port(void){__builtin_trap();}
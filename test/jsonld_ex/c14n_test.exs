@@ -25,5 +25,35 @@ defmodule JsonldEx.C14nTest do
     assert is_binary(nq)
     assert byte_size(nq) > 0
   end
-end
 
+  test "c14n can return the input to canonical blank node mapping" do
+    doc = %{
+      "@id" => "http://example.com/alice",
+      "http://schema.org/knows" => [
+        %{"@id" => "_:friend", "http://schema.org/name" => "Bob"},
+        %{"@id" => "_:other", "http://schema.org/name" => "Carol"}
+      ]
+    }
+
+    {:ok, %{nquads: nquads, bnode_map: bnode_map}} = JSONLD.c14n(doc, bnode_map: true)
+
+    assert map_size(bnode_map) == 2
+    assert bnode_map |> Map.values() |> Enum.sort() == ["_:c14n0", "_:c14n1"]
+
+    # Each input node's canonical label carries that node's name in the output
+    for {input, name} <- [{"_:friend", "Bob"}, {"_:other", "Carol"}] do
+      assert nquads =~ ~s(#{bnode_map[input]} <http://schema.org/name> "#{name}" .)
+    end
+
+    refute nquads =~ "_:friend"
+  end
+
+  test "isomorphic documents canonicalize identically" do
+    a = %{"@id" => "_:x", "http://schema.org/knows" => %{"@id" => "_:y", "http://schema.org/name" => "Bob"}}
+    b = %{"@id" => "_:q", "http://schema.org/knows" => %{"@id" => "_:p", "http://schema.org/name" => "Bob"}}
+
+    {:ok, %{nquads: nq_a}} = JSONLD.c14n(a)
+    {:ok, %{nquads: nq_b}} = JSONLD.c14n(b)
+    assert nq_a == nq_b
+  end
+end
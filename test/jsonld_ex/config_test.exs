@@ -0,0 +1,101 @@
+defmodule JsonldEx.ConfigTest do
+  # Default options are process-wide, so these tests must not overlap others
+  use ExUnit.Case, async: false
+
+  alias JsonldEx.{Config, Native}
+
+  setup do
+    previous = Config.get_default_options()
+    on_exit(fn -> Config.set_default_options(previous) end)
+    :ok
+  end
+
+  @bad_id Jason.encode!(%{"@context" => %{"@vocab" => "http://example.org/"}, "@id" => 42, "name" => "A"})
+
+  test "defaults round trip as strings" do
+    assert :ok = Config.set_default_options(strict: true, processing_mode: "json-ld-1.0")
+    assert Config.get_default_options() == %{"strict" => "true", "processing_mode" => "json-ld-1.0"}
+  end
+
+  test "calls pick up the defaults" do
+    assert {:ok, _} = Native.expand(@bad_id, [])
+
+    :ok = Config.set_default_options(%{"strict" => "true"})
+    assert {:error, {:invalid_id_value, _}} = Native.expand(@bad_id, [])
+  end
+
+  test "per-call options override the defaults" do
+    :ok = Config.set_default_options(%{"strict" => "true"})
+    assert {:ok, _} = Native.expand(@bad_id, [{"strict", "false"}])
+  end
+
+  test "only processing knobs are merged into calls" do
+    {:ok, _} = Native.cache_context(~s({"@vocab": "http://example.org/"}), "http://example.org/a", [{"namespace", "tenant-a"}])
+    {:ok, _} = Native.cache_context(~s({"@vocab": "http://example.org/"}), "http://example.org/b", [{"namespace", "tenant-b"}])
+    :ok = Config.set_default_options(%{"namespace" => "tenant-a", "format" => "delta"})
+
+    {:ok, ops} = Native.compute_lcs_array("[1, 2]", "[1, 3]", [])
+    assert is_list(Jason.decode!(ops))
+
+    {:ok, _} = Native.clear_caches([])
+    {:ok, stats} = Native.context_cache_stats([])
+    assert Enum.all?(Jason.decode!(stats)["namespaces"], fn {_, counts} -> counts["entries"] == 0 end)
+  end
+
+  test "defaults that aren't string pairs are refused and the old ones kept" do
+    :ok = Native.set_default_options(%{"strict" => "true"})
+    assert {:error, _} = Native.set_default_options([:strict])
+    assert Native.get_default_options() == %{"strict" => "true"}
+  end
+
+  test "load_data turns the application environment into string pairs" do
+    Application.put_env(:jsonld_ex, :default_options, profile: true)
+    on_exit(fn -> Application.delete_env(:jsonld_ex, :default_options) end)
+
+    assert Config.load_data() == [{"profile", "true"}]
+  end
+
+  describe "incidents" do
+    setup do
+      Native.clear_incidents()
+      :ok
+    end
+
+    defp incidents do
+      {:ok, json} = Native.recent_incidents()
+      Jason.decode!(json)
+    end
+
+    test "a call over a threshold leaves a record" do
+      :ok = Config.set_default_options(%{"large_output_bytes" => "10"})
+      {:ok, _} = Native.expand(@bad_id, [{"strict", "false"}])
+
+      assert [incident] = incidents()
+      assert %{"operation" => "expand", "exceeded" => ["large_output_bytes"], "options" => %{"strict" => "false"}} = incident
+      assert incident["input_bytes"] == byte_size(@bad_id)
+      assert incident["output_bytes"] > 10
+      assert String.length(incident["document_hash"]) == 16
+    end
+
+    test "diffs are checked against large_delta_ops" do
+      :ok = Config.set_default_options(%{"large_delta_ops" => "1"})
+      {:ok, _} = Native.diff_structural(~s({"a": 1, "b": 2}), ~s({"a": 1, "b": 3}), [])
+      assert incidents() == []
+
+      {:ok, _} = Native.diff_structural(~s({"a": 1, "b": 2}), ~s({"a": 2, "b": 3}), [])
+      assert [%{"operation" => "diff_structural", "delta_ops" => 2, "exceeded" => ["large_delta_ops"]}] = incidents()
+    end
+
+    test "nothing is recorded without thresholds, and clear_incidents empties the buffer" do
+      {:ok, _} = Native.expand(@bad_id, [])
+      assert incidents() == []
+
+      :ok = Config.set_default_options(%{"large_output_bytes" => "1"})
+      for _ <- 1..105, do: Native.expand(@bad_id, [])
+      assert length(incidents()) == 100
+
+      assert :ok = Native.clear_incidents()
+      assert incidents() == []
+    end
+  end
+end
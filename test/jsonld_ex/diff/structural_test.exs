@@ -241,6 +241,34 @@ defmodule JsonldEx.Diff.StructuralTest do
       assert diff["value"] == [nil, "something"]
     end
 
+    test "changing a value to null is a change, not a delete" do
+      old = %{"name" => "John", "age" => 30}
+      new = %{"name" => "John", "age" => nil}
+
+      {:ok, diff} = Structural.diff(old, new)
+      assert diff["age"] == [30, nil]
+
+      {:ok, patched} = Structural.patch(old, diff)
+      assert patched == new
+      assert Map.has_key?(patched, "age")
+    end
+
+    test "removing a null-valued key is a delete" do
+      old = %{"name" => "John", "age" => nil}
+      new = %{"name" => "John"}
+
+      {:ok, diff} = Structural.diff(old, new)
+      assert diff["age"] == [nil, 0, 0]
+
+      {:ok, patched} = Structural.patch(old, diff)
+      assert patched == new
+    end
+
+    test "a trailing null array item is diffed like any other" do
+      {:ok, diff} = Structural.diff(%{"items" => [1, nil]}, %{"items" => [1]}, array_diff: :simple)
+      assert diff["items"]["_1"] == [nil, 0, 0]
+    end
+
     test "handles empty objects" do
       old = %{}
       new = %{"name" => "John"}
@@ -0,0 +1,4014 @@
+defmodule JsonldEx.NativeTest do
+  use ExUnit.Case, async: true
+
+  alias JsonldEx.Native
+
+  describe "named graphs" do
+    setup do
+      doc = %{
+        "@graph" => [
+          %{"@id" => "http://example.org/bob", "http://schema.org/name" => "Bob"},
+          %{
+            "@id" => "http://example.org/graphs/1",
+            "@graph" => [
+              %{"@id" => "http://example.org/alice", "http://schema.org/name" => "Alice"}
+            ]
+          }
+        ]
+      }
+
+      {:ok, doc: Jason.encode!(doc)}
+    end
+
+    test "flatten attributes nodes to their named graph", %{doc: doc} do
+      {:ok, json} = Native.flatten(doc, nil, [])
+      %{"@graph" => nodes} = Jason.decode!(json)
+
+      ids = Enum.map(nodes, & &1["@id"])
+      assert "http://example.org/bob" in ids
+      refute "http://example.org/alice" in ids
+
+      graph = Enum.find(nodes, &(&1["@id"] == "http://example.org/graphs/1"))
+      assert [%{"@id" => "http://example.org/alice"}] = graph["@graph"]
+    end
+
+    test "to_rdf emits the graph name only for named graph quads", %{doc: doc} do
+      {:ok, nquads} = Native.to_rdf(doc, [])
+      lines = String.split(nquads, "\n", trim: true)
+
+      assert "<http://example.org/alice> <http://schema.org/name> \"Alice\" <http://example.org/graphs/1> ." in lines
+      assert "<http://example.org/bob> <http://schema.org/name> \"Bob\" ." in lines
+    end
+  end
+
+  describe "idempotent operational patches" do
+    setup do
+      patch = %{
+        "operations" => [
+          %{"type" => "insert", "path" => ["items", 2], "value" => 3, "timestamp" => 1, "op_id" => "op-1"}
+        ]
+      }
+
+      {:ok, doc: Jason.encode!(%{"items" => [1, 2]}), patch: Jason.encode!(patch)}
+    end
+
+    test "generated operations carry op ids when requested" do
+      {:ok, diff} = Native.diff_operational(~s({"name":"a"}), ~s({"name":"b"}), [{"op_ids", "true"}])
+      assert [%{"op_id" => op_id}] = Jason.decode!(diff)["operations"]
+      assert is_binary(op_id)
+    end
+
+    test "reapplying a patch tracked under a metadata key is a no-op", %{doc: doc, patch: patch} do
+      opts = [{"applied_ops_key", "_applied_ops"}]
+      {:ok, once} = Native.patch_operational(doc, patch, opts)
+      {:ok, twice} = Native.patch_operational(once, patch, opts)
+
+      assert twice == once
+      assert Jason.decode!(twice)["items"] == [1, 2, 3]
+    end
+
+    test "reapplying a patch tracked in a caller state blob is a no-op", %{doc: doc, patch: patch} do
+      {:ok, once, state} = Native.patch_operational(doc, patch, [{"applied_ops", "[]"}])
+      {:ok, twice, ^state} = Native.patch_operational(once, patch, [{"applied_ops", state}])
+
+      assert twice == once
+      assert Jason.decode!(state) == ["op-1"]
+    end
+
+    test "merge_diffs_operational dedupes by op id", %{patch: patch} do
+      {:ok, merged} = Native.merge_diffs_operational("[#{patch},#{patch}]", [])
+      assert length(Jason.decode!(merged)["operations"]) == 1
+    end
+  end
+
+  describe "merge_documents context reconciliation" do
+    setup do
+      docs = [
+        Jason.encode!(%{"@context" => %{"name" => "http://schema.org/name"}, "@id" => "http://example.com/a", "name" => "Alice"}),
+        Jason.encode!(%{"@context" => %{"name" => "http://xmlns.com/foaf/0.1/name"}, "@id" => "http://example.com/a", "name" => "Bob"})
+      ]
+
+      {:ok, docs: docs}
+    end
+
+    test "conflicting term definitions are an error by default", %{docs: docs} do
+      assert {:error, {:context_conflict, ["name"]}} = Native.merge_documents(docs, [])
+    end
+
+    test "conflicting terms can be namespaced per document", %{docs: docs} do
+      {:ok, json} = Native.merge_documents(docs, [{"context_conflicts", "namespace"}])
+      merged = Jason.decode!(json)
+
+      assert merged["@context"] == %{
+               "name" => "http://schema.org/name",
+               "name_1" => "http://xmlns.com/foaf/0.1/name"
+             }
+
+      assert merged["@id"] == "http://example.com/a"
+      assert merged["name"] == "Alice"
+      assert merged["name_1"] == "Bob"
+    end
+
+    test "each document is expanded under its own context before compacting" do
+      docs = [
+        Jason.encode!(%{"@context" => %{"ex" => "http://a.example/"}, "@id" => "http://example.com/n", "@type" => "ex:Thing"}),
+        Jason.encode!(%{
+          "@context" => %{"ex" => "http://b.example/", "link" => %{"@id" => "http://b.example/link", "@type" => "@id"}},
+          "@id" => "http://example.com/n",
+          "link" => "ex:z",
+          "http://b.example/note" => "ex:literal"
+        })
+      ]
+
+      {:ok, json} = Native.merge_documents(docs, [{"context_conflicts", "namespace"}])
+      merged = Jason.decode!(json)
+
+      assert merged["@context"]["ex_1"] == "http://b.example/"
+      assert merged["@type"] == "ex:Thing"
+      assert merged["link"] == "ex_1:z"
+      # plain string literals are not IRIs, whatever they look like
+      assert merged["ex_1:note"] == "ex:literal"
+    end
+
+    test "nodes without a shared @id stay separate" do
+      docs = [
+        Jason.encode!(%{"@context" => %{"name" => "http://schema.org/name"}, "name" => "Alice"}),
+        Jason.encode!(%{"@context" => %{"name" => "http://schema.org/name"}, "name" => "Bob"})
+      ]
+
+      {:ok, json} = Native.merge_documents(docs, [])
+      names = json |> Jason.decode!() |> Map.fetch!("@graph") |> Enum.map(& &1["name"])
+
+      assert Enum.sort(names) == ["Alice", "Bob"]
+    end
+
+    test "an unparsable document is an error naming its index" do
+      docs = [Jason.encode!(%{"http://schema.org/name" => "Alice"}), "{not json"]
+
+      assert {:error, message} = Native.merge_documents(docs, [])
+      assert message =~ "document 1"
+    end
+  end
+
+  describe "absolute IRI detection" do
+    setup do
+      doc = %{
+        "@id" => "urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6",
+        "@type" => "schema:Person",
+        "http://schema.org/knows" => %{"@id" => "did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK"},
+        "http://schema.org/sameAs" => %{"@id" => "foo:bar"}
+      }
+
+      {:ok, doc: Jason.encode!(doc)}
+    end
+
+    test "non-http schemes are kept as absolute IRIs", %{doc: doc} do
+      for expand <- [&Native.expand/2, &Native.expand_binary/2] do
+        {:ok, json} = expand.(doc, [])
+        [node] = Jason.decode!(json)
+
+        assert node["@id"] == "urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6"
+        assert node["http://schema.org/knows"]["@id"] ==
+                 "did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK"
+        assert node["http://schema.org/sameAs"]["@id"] == "foo:bar"
+      end
+    end
+
+    test "compact IRIs with a defined prefix still expand", %{doc: doc} do
+      {:ok, json} = Native.expand_binary(doc, [])
+      assert [%{"@type" => "http://schema.org/Person"}] = Jason.decode!(json)
+    end
+
+    test "did:, urn: and mailto: IRIs are absolute wherever an IRI is expected" do
+      doc = %{
+        "@context" => %{
+          "@vocab" => "http://example.com/vocab/",
+          "contact" => %{"@id" => "http://schema.org/contactPoint", "@type" => "@id"}
+        },
+        "@id" => "did:example:123",
+        "@type" => "urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6",
+        "contact" => ["mailto:a@b", "did:example:456"],
+        "mailto:a@b" => "property named by a mailto IRI"
+      }
+
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+      [node] = Jason.decode!(json)
+
+      assert node["@id"] == "did:example:123"
+      assert node["@type"] == "urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6"
+      assert node["http://schema.org/contactPoint"] == [%{"@id" => "mailto:a@b"}, %{"@id" => "did:example:456"}]
+      assert Map.has_key?(node, "mailto:a@b")
+    end
+
+    test "diff_semantic reads non-http IRIs as references only where the term coerces them" do
+      context = %{"email" => %{"@id" => "http://schema.org/email", "@type" => "@id"}}
+      coerced = Jason.encode!(%{"@context" => context, "@id" => "did:example:123", "email" => "mailto:a@b"})
+      reference = Jason.encode!(%{"@id" => "did:example:123", "http://schema.org/email" => %{"@id" => "mailto:a@b"}})
+      literal = Jason.encode!(%{"@id" => "did:example:123", "http://schema.org/email" => "mailto:a@b"})
+
+      {:ok, json} = Native.diff_semantic(coerced, reference, [])
+      assert %{"metadata" => %{"semantic_equivalence" => true}} = Jason.decode!(json)
+
+      # without coercion the string is a literal, whatever it looks like
+      {:ok, json} = Native.diff_semantic(literal, reference, [])
+      assert [%{"object" => %{"value" => "mailto:a@b"}}] = Jason.decode!(json)["removed_triples"]
+    end
+  end
+
+  describe "@nest expansion" do
+    test "array-valued nests contribute every nested property to the node" do
+      doc = %{
+        "@context" => %{
+          "@vocab" => "http://schema.org/",
+          "details" => "@nest",
+          "label" => %{"@id" => "http://www.w3.org/2000/01/rdf-schema#label", "@nest" => "details"}
+        },
+        "@id" => "http://example.org/a",
+        "name" => "A",
+        "details" => [%{"label" => "one"}, %{"label" => "two"}]
+      }
+
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+      [node] = Jason.decode!(json)
+
+      refute Map.has_key?(node, "details")
+      assert node["http://schema.org/name"] == %{"@value" => "A"}
+      assert node["http://www.w3.org/2000/01/rdf-schema#label"] == [%{"@value" => "one"}, %{"@value" => "two"}]
+    end
+
+    test "a scalar under a nest key is an error" do
+      doc = %{"@context" => %{"details" => "@nest"}, "@id" => "http://example.org/a", "details" => "oops"}
+
+      assert {:error, {:invalid_nest_value, _}} = Native.expand(Jason.encode!(doc), [])
+      assert {:error, {:invalid_nest_value, _}} = Native.expand(~s({"@nest": {"@value": "x"}}), [])
+    end
+
+    test "a term's @nest must be a string naming @nest or an alias of it" do
+      for nest <- ["details", "@id", 5] do
+        context = %{"details" => "http://example.org/details", "label" => %{"@id" => "http://example.org/label", "@nest" => nest}}
+        doc = %{"@context" => context, "@id" => "http://example.org/a", "label" => "one"}
+
+        assert {:error, {:invalid_nest_value, _}} = Native.expand(Jason.encode!(doc), []), "@nest #{inspect(nest)}"
+      end
+    end
+  end
+
+  describe "IRI normalization" do
+    test "normalize_iri applies syntax-based normalization" do
+      assert {:ok, "http://example.com/~user/a%2Fb"} =
+               Native.normalize_iri("HTTP://Example.COM:80/%7euser/a%2fb", [])
+
+      assert {:ok, "urn:isbn:0451450523"} = Native.normalize_iri("URN:isbn:0451450523", [])
+    end
+
+    test "dot segments are only removed when a base is present" do
+      assert {:ok, "http://example.com/a/./b/../c"} = Native.normalize_iri("http://example.com/a/./b/../c", [])
+
+      assert {:ok, "http://example.com/a/c"} =
+               Native.normalize_iri("http://example.com/a/./b/../c", [{"base", "http://example.com/"}])
+    end
+
+    test "nfc normalization is opt-in" do
+      decomposed = "http://example.com/cafe\u0301"
+      assert {:ok, ^decomposed} = Native.normalize_iri(decomposed, [])
+      assert {:ok, "http://example.com/caf\u00e9"} = Native.normalize_iri(decomposed, [{"nfc", "true"}])
+    end
+
+    test "normalized and original forms expand to the same node" do
+      doc =
+        Jason.encode!([
+          %{"@id" => "HTTP://Example.COM:80/%7euser", "http://schema.org/name" => "A"},
+          %{"@id" => "http://example.com/~user", "HTTP://schema.org/name" => "B"}
+        ])
+
+      {:ok, json} = Native.expand(doc, [{"normalize_iris", "true"}])
+      [first, second] = Jason.decode!(json)
+
+      assert first["@id"] == "http://example.com/~user"
+      assert first["@id"] == second["@id"]
+      assert Map.has_key?(second, "http://schema.org/name")
+
+      {:ok, raw} = Native.expand(doc, [])
+      [first, second] = Jason.decode!(raw)
+      refute first["@id"] == second["@id"]
+    end
+
+    test "escapes in a host keep uppercase hex digits" do
+      assert {:ok, "http://ex%C3%A4mple.com/"} = Native.normalize_iri("http://EX%c3%a4MPLE.com/", [])
+      assert {:ok, "http://example.com/"} = Native.normalize_iri("http://%45xample.com/", [])
+    end
+
+    test "properties that normalize to the same IRI keep both values" do
+      doc = Jason.encode!(%{"http://schema.org/name" => "A", "HTTP://schema.org/name" => "B"})
+
+      {:ok, json} = Native.expand(doc, [{"normalize_iris", "true"}])
+      [node] = Jason.decode!(json)
+
+      assert node |> Map.fetch!("http://schema.org/name") |> Enum.map(& &1["@value"]) |> Enum.sort() == ["A", "B"]
+    end
+  end
+
+  describe "query_nodes limits" do
+    setup do
+      doc = %{
+        "@type" => "Person",
+        "knows" => [
+          %{"@type" => "Person", "name" => "a"},
+          %{"@type" => "Person", "name" => "b", "knows" => %{"@type" => "Person", "name" => "c"}}
+        ]
+      }
+
+      {:ok, doc: Jason.encode!(doc), pattern: Jason.encode!(%{"@type" => "Person"})}
+    end
+
+    test "limit truncates the matches", %{doc: doc, pattern: pattern} do
+      {:ok, all} = Native.query_nodes(doc, pattern)
+      assert length(Jason.decode!(all)) == 4
+
+      {:ok, limited} = Native.query_nodes(doc, pattern, [{"limit", "2"}])
+      assert length(Jason.decode!(limited)) == 2
+    end
+
+    test "max_depth stops descending past N levels", %{doc: doc, pattern: pattern} do
+      {:ok, root_only} = Native.query_nodes(doc, pattern, [{"max_depth", "0"}])
+      assert length(Jason.decode!(root_only)) == 1
+
+      {:ok, one_level} = Native.query_nodes(doc, pattern, [{"max_depth", "1"}])
+      names = one_level |> Jason.decode!() |> Enum.map(& &1["name"])
+      assert names == [nil, "a", "b"]
+    end
+  end
+
+  describe "blank node relabeling" do
+    setup do
+      doc = %{
+        "@id" => "_:x",
+        "http://schema.org/knows" => [%{"@id" => "_:b0"}, %{"@id" => "_:x"}]
+      }
+
+      {:ok, doc: Jason.encode!(doc)}
+    end
+
+    test "expand rewrites incoming labels and returns the mapping", %{doc: doc} do
+      {:ok, json, metadata} = Native.expand(doc, [{"relabel_bnodes", "true"}])
+      [node] = Jason.decode!(json)
+
+      assert %{"bnode_map" => %{"_:x" => "_:b0", "_:b0" => "_:b1"}} = Jason.decode!(metadata)
+      assert node["@id"] == "_:b0"
+      # co-reference within the document is preserved
+      assert [%{"@id" => "_:b1"}, %{"@id" => "_:b0"}] = node["http://schema.org/knows"]
+    end
+
+    test "flatten labels never collide with incoming ones" do
+      doc = %{"@id" => "_:b0", "http://schema.org/knows" => %{"http://schema.org/name" => "anon"}}
+      {:ok, json, metadata} = Native.flatten(Jason.encode!(doc), nil, [{"relabel_bnodes", "true"}])
+
+      ids = json |> Jason.decode!() |> Map.fetch!("@graph") |> Enum.map(& &1["@id"])
+      assert ids == Enum.uniq(ids)
+      assert length(ids) == 2
+      assert %{"bnode_map" => %{"_:b0" => _}} = Jason.decode!(metadata)
+    end
+
+    test "merge_documents prefixes labels per document" do
+      docs = [
+        Jason.encode!(%{"@id" => "_:b0", "http://schema.org/name" => "A"}),
+        Jason.encode!(%{"http://schema.org/knows" => %{"@id" => "_:b0"}})
+      ]
+
+      {:ok, json, metadata} = Native.merge_documents(docs, [{"relabel_bnodes", "true"}])
+      [named, knows] = json |> Jason.decode!() |> Map.fetch!("@graph")
+
+      assert named["@id"] == "_:d0b0"
+      assert knows["http://schema.org/knows"]["@id"] == "_:d1b1"
+      assert %{"bnode_map" => [%{"_:b0" => "_:d0b0"}, %{"_:b0" => "_:d1b1"}]} = Jason.decode!(metadata)
+    end
+  end
+
+  describe "dependency graph DOT output" do
+    setup do
+      graph = %{
+        "nodes" => [
+          %{"id" => 0, "name" => "core"},
+          %{"id" => 1, "name" => "app"},
+          %{"id" => 2, "name" => "plugin"}
+        ],
+        "edges" => [
+          %{"from" => 0, "to" => 1},
+          %{"from" => 1, "to" => 2},
+          %{"from" => 2, "to" => 1}
+        ]
+      }
+
+      {:ok, graph: Jason.encode!(graph)}
+    end
+
+    test "emits node and edge declarations", %{graph: graph} do
+      {:ok, dot} = Native.dependency_graph_dot(graph, [])
+
+      assert dot =~ ~r/^digraph dependencies \{/
+      assert dot =~ ~s("0" [label="core"];)
+      assert dot =~ ~s("1" [label="app"];)
+      assert dot =~ ~s("0" -> "1";)
+      assert dot =~ ~s("2" -> "1";)
+    end
+
+    test "colors nodes that are part of a cycle", %{graph: graph} do
+      {:ok, dot} = Native.dependency_graph_dot(graph, [{"highlight_cycles", "true"}])
+
+      assert dot =~ ~s("0" [label="core"];)
+      assert dot =~ ~s("1" [label="app", color=red, fontcolor=red];)
+      assert dot =~ ~s("2" [label="plugin", color=red, fontcolor=red];)
+      assert {:ok, [["app", "plugin"]]} = Native.detect_cycles(graph)
+    end
+  end
+
+  describe "keyword handling" do
+    setup do
+      doc = %{
+        "@id" => "http://example.org/a",
+        "http://example.org/knows" => [%{"@id" => "http://example.org/b", "@foo" => "bar"}]
+      }
+
+      {:ok, doc: Jason.encode!(doc)}
+    end
+
+    test "unknown keywords are dropped in lenient mode", %{doc: doc} do
+      {:ok, json} = Native.expand(doc, [])
+      [node] = Jason.decode!(json)
+
+      assert node["http://example.org/knows"] == [%{"@id" => "http://example.org/b"}]
+    end
+
+    test "unknown keywords are an error with their path in strict mode", %{doc: doc} do
+      assert {:error, {:unknown_keyword, message}} = Native.expand(doc, [{"strict", "true"}])
+      assert message =~ "/http:~1~1example.org~1knows/0/@foo"
+    end
+
+    test "terms and IRIs with the form of a keyword are ignored" do
+      # expand 0119/0120: @ignoreMe can't be defined, and terms mapping to it are dropped
+      doc = %{
+        "@context" => %{"@ignoreMe" => "http://example.org/ignored", "ignored" => %{"@id" => "@ignoreMe"}},
+        "ignored" => "x",
+        "http://example.org/kept" => "y"
+      }
+
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+      assert [%{"http://example.org/kept" => _} = node] = Jason.decode!(json)
+      assert map_size(node) == 1
+    end
+
+    test "keys starting with @ but without the form of a keyword are terms" do
+      # expand t0119 "Ignore some terms with @, allow others."
+      doc = %{
+        "@context" => %{
+          "@vocab" => "http://example.org/vocab#",
+          "@" => "http://example.org/vocab#at",
+          "@foo.bar" => "http://example.org/foo.bar",
+          "@ignoreMe" => "http://example.org/ignoreMe"
+        },
+        "@" => "allowed",
+        "@foo.bar" => "allowed",
+        "@ignoreMe" => "ignored"
+      }
+
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+      [node] = Jason.decode!(json)
+
+      assert Map.keys(node) == ["http://example.org/foo.bar", "http://example.org/vocab#at"]
+      assert {:error, {:unknown_keyword, message}} = Native.expand(Jason.encode!(doc), [{"strict", "true"}])
+      assert message =~ "/@ignoreMe"
+    end
+
+    test "keywords cannot be redefined" do
+      # keyword redefinitions of the kind the expansion error suite checks
+      for context <- [
+            %{"@type" => "http://example.org/type"},
+            %{"@id" => %{"@id" => "http://example.org/id"}},
+            %{"@type" => %{"@id" => "http://example.org/type", "@container" => "@set"}}
+          ] do
+        doc = Jason.encode!(%{"@context" => context, "@type" => "x"})
+        assert {:error, {:keyword_redefinition, _}} = Native.expand(doc, [])
+        assert {:error, {:keyword_redefinition, _}} = Native.expand(doc, [{"strict", "true"}])
+      end
+    end
+
+    test "@type may only be declared as a set" do
+      doc = Jason.encode!(%{"@context" => %{"@type" => %{"@container" => "@set"}}, "@type" => "x"})
+      assert {:ok, _} = Native.expand(doc, [])
+    end
+
+    test "@json stays a value object's @type" do
+      doc = ~s([{"http://example.org/p":{"@value":{"Key":"V"},"@type":"@json"}}])
+      {:ok, json} = Native.expand(doc, [])
+
+      assert [%{"http://example.org/p" => %{"@value" => %{"Key" => "V"}, "@type" => "@json"}}] = Jason.decode!(json)
+    end
+  end
+
+  defp round_trip(doc, context) do
+    {:ok, expanded} = Native.expand(Jason.encode!(Map.put(doc, "@context", context)), [])
+    {:ok, compacted} = Native.compact(expanded, Jason.encode!(context), [])
+    Jason.decode!(compacted)
+  end
+
+  describe "@index round trip" do
+    setup do
+      context = %{"ex" => "http://example.org/", "items" => "ex:items", "g" => "ex:g"}
+      %{context: context}
+    end
+
+    test "an indexed @list keeps its @index", %{context: context} do
+      doc = %{"@id" => "ex:a", "items" => %{"@list" => ["a", "b"], "@index" => "first"}}
+
+      compacted = round_trip(doc, context)
+      assert compacted["items"] == %{"@list" => ["a", "b"], "@index" => "first"}
+      assert compacted["@id"] == "ex:a"
+    end
+
+    test "an indexed @list is not folded into an @list container" do
+      context = %{"ex" => "http://example.org/", "items" => %{"@id" => "ex:items", "@container" => "@list"}}
+      doc = %{"@id" => "ex:a", "items" => %{"@list" => ["a", "b"], "@index" => "first"}}
+
+      compacted = round_trip(doc, context)
+      assert compacted["http://example.org/items"] == %{"@list" => ["a", "b"], "@index" => "first"}
+    end
+
+    test "an indexed graph object keeps its @index", %{context: context} do
+      doc = %{"@id" => "ex:a", "g" => %{"@graph" => %{"@id" => "ex:b", "ex:p" => "v"}, "@index" => "idx"}}
+
+      compacted = round_trip(doc, context)
+      assert compacted["g"] == %{"@graph" => [%{"@id" => "ex:b", "http://example.org/p" => "v"}], "@index" => "idx"}
+    end
+  end
+
+  describe "document_stats" do
+    test "profiles shape as well as size" do
+      doc = ~s({"@context":{"ex":"http://example.org/"},"@id":"ex:a","ex:knows":[{"@type":"ex:T","@context":{"ex":"http://example.org/"}},[["deep"]]],"ex:note":"longest string"})
+
+      assert {:ok, json} = Native.document_stats(doc)
+      stats = Jason.decode!(json)
+
+      assert stats["byte_size"] == byte_size(doc)
+      assert stats["max_depth"] == 4
+      assert stats["objects"] == 4
+      assert stats["arrays"] == 3
+      assert stats["strings"] == 6
+      assert stats["nodes"] == 2
+      assert stats["contexts"] == 1
+      assert stats["largest_string"] == byte_size("longest string")
+    end
+
+    test "deep but tiny documents report their depth" do
+      doc = String.duplicate("[", 100) <> String.duplicate("]", 100)
+
+      assert {:ok, json} = Native.document_stats(doc)
+      stats = Jason.decode!(json)
+      assert stats["max_depth"] == 100
+      assert stats["byte_size"] == 200
+    end
+  end
+
+  describe "reusable processors" do
+    setup do
+      context = %{
+        "@context" => %{
+          "name" => "http://schema.org/name",
+          "knows" => %{"@id" => "http://schema.org/knows", "@type" => "@id"}
+        }
+      }
+
+      {:ok, processor} = Native.processor_new(Jason.encode!(context), [])
+      %{processor: processor}
+    end
+
+    test "expands multiple documents against the compiled context", %{processor: processor} do
+      docs = [
+        %{"name" => "Alice", "knows" => "http://example.org/bob"},
+        %{"name" => "Bob"},
+        %{"@id" => "http://example.org/carol", "name" => "Carol"}
+      ]
+
+      expanded =
+        for doc <- docs do
+          assert {:ok, json} = Native.processor_expand(processor, Jason.encode!(doc))
+          [node] = Jason.decode!(json)
+          node
+        end
+
+      assert Enum.map(expanded, & &1["http://schema.org/name"]) ==
+               [%{"@value" => "Alice"}, %{"@value" => "Bob"}, %{"@value" => "Carol"}]
+
+      assert hd(expanded)["http://schema.org/knows"] == %{"@id" => "http://example.org/bob"}
+    end
+
+    test "compacts against the compiled context", %{processor: processor} do
+      expanded = [%{"http://schema.org/knows" => [%{"@id" => "http://example.org/bob"}]}]
+
+      assert {:ok, json} = Native.processor_compact(processor, Jason.encode!(expanded))
+      assert %{"knows" => "http://example.org/bob", "@context" => %{"name" => _}} = Jason.decode!(json)
+    end
+
+    test "invalid contexts are rejected up front" do
+      context = %{"@context" => %{"a" => %{"@id" => "b:x"}, "b" => %{"@id" => "a:y"}}}
+      assert {:error, {:cyclic_iri_mapping, _}} = Native.processor_new(Jason.encode!(context), [])
+    end
+  end
+
+  describe "patch_structural fixtures" do
+    # Expected outputs were captured from the copying implementation before
+    # patching was made in-place; the two must stay byte-for-byte identical
+    @patch_fixtures [
+      {~s({"name":"John","age":30}), ~s({"name":["John","Jane"],"age":[30,0,0],"city":["NYC"]}),
+       ~s({"city":"NYC","name":"Jane"})},
+      {~s({"person":{"name":"John","tags":["a","b"]}}),
+       ~s({"person":{"name":["John","Jane"],"tags":{"_t":"a","_1":["b",0,0],"1":["c"]}}}),
+       ~s({"person":{"name":"Jane","tags":["a","c"]}})},
+      {~s({"items":[1,2,3,4]}), ~s({"items":{"_t":"a","_3":["",0,3]}}), ~s({"items":[2,3,4,1]})},
+      {~s({"items":[{"v":1},{"v":2},{"v":3}]}), ~s({"items":{"_t":"a","_0":[{"v":1},0,0],"_2":{"v":[3,30]}}}),
+       ~s({"items":[{"v":2},{"v":3}]})},
+      {~s({"items":[{"v":1},{"v":2}]}), ~s({"items":{"_t":"a","_1":{"v":[2,20]},"2":[{"v":3}]}}),
+       ~s({"items":[{"v":1},{"v":20},{"v":3}]})},
+      {~s({"text":"hello world"}),
+       ~s({"text":[{"text_diff":[{"op":"equal","old_range":[0,6],"new_range":[0,6]},{"op":"replace","old_range":[6,11],"new_range":[6,11],"new_text":"there"}]},0,2]}),
+       ~s({"text":"hello there"})},
+      {~s({"a":{"b":{"c":1}}}), ~s({"a":{"b":{"c":[1,2],"d":["new"]}}}), ~s({"a":{"b":{"c":2,"d":"new"}}})},
+      {~s([1,2,3]), ~s({"_t":"a","_0":[1,0,0],"2":[9]}), ~s([2,3,9])},
+      {~s([{"a":1},{"b":2},3]), ~s({"_t":"a","_2":["",0,3],"_0":{"a":[1,9]}}), ~s([{"a":9},3,{"a":1}])}
+    ]
+
+    test "delta fixtures produce the recorded output" do
+      for {doc, patch, expected} <- @patch_fixtures do
+        assert {:ok, result} = Native.patch_structural(doc, patch, [])
+        assert result == expected, "patching #{doc} with #{patch}"
+      end
+    end
+
+    test "diffs generated by diff_structural apply cleanly" do
+      old = %{"name" => "John", "tags" => ["a", "b", "c"], "address" => %{"city" => "NYC", "zip" => "10001"}}
+      new = %{"name" => "Jane", "tags" => ["a", "b", "c", "d"], "address" => %{"city" => "Boston"}}
+
+      {:ok, diff} = Native.diff_structural(Jason.encode!(old), Jason.encode!(new), [])
+      {:ok, patched} = Native.patch_structural(Jason.encode!(old), diff, [])
+      assert Jason.decode!(patched) == new
+    end
+
+    test "array moves replay to the new order" do
+      pairs = [
+        {[1, 2, 3, 4], [4, 1, 2, 3]},
+        {[1, 2, 3, 4], [2, 3, 4, 1]},
+        {~w(a b c d e), ~w(e a c b d)},
+        {~w(x a b c), ~w(c a y b)},
+        {[%{"k" => 1}, "a", "b", "c"], ["c", %{"k" => 2}, "a", "d"]}
+      ]
+
+      for {old, new} <- pairs do
+        {:ok, diff} = Native.diff_structural(Jason.encode!(old), Jason.encode!(new), [])
+        {:ok, patched} = Native.patch_structural(Jason.encode!(old), diff, [])
+        assert Jason.decode!(patched) == new, "#{inspect(old)} -> #{inspect(new)} with #{diff}"
+      end
+
+      {:ok, diff} = Native.diff_structural("[1,2,3,4]", "[4,1,2,3]", [])
+      assert Map.delete(Jason.decode!(diff), "_t") == %{"_0" => ["", 3, 3]}
+    end
+  end
+
+  describe "collect_warnings" do
+    @warn_doc Jason.encode!(%{
+                "@context" => %{"ex" => "http://example.org/", "secret" => nil},
+                "@id" => "ex:a",
+                "secret" => "hidden",
+                "ex:label" => %{"@value" => "x", "@language" => 5},
+                "nope:thing" => "y"
+              })
+
+    test "dropped terms are reported with their path" do
+      assert {:ok, json, metadata} = Native.expand(@warn_doc, [{"collect_warnings", "true"}])
+      warnings = Jason.decode!(metadata)["warnings"]
+
+      assert %{"code" => "dropped_term", "path" => "/secret"} =
+               Enum.find(warnings, &(&1["code"] == "dropped_term"))
+
+      [node] = Jason.decode!(json)
+      refute Enum.any?(Map.values(node), &(&1 == %{"@value" => "hidden"}))
+    end
+
+    test "invalid value object entries and unresolved prefixes are reported" do
+      {:ok, _json, metadata} = Native.expand(@warn_doc, [{"collect_warnings", "true"}])
+      by_code = Jason.decode!(metadata)["warnings"] |> Enum.group_by(& &1["code"], & &1["path"])
+
+      assert by_code["invalid_value_object"] == ["/ex:label"]
+      assert by_code["unresolved_prefix"] == ["/nope:thing"]
+    end
+
+    test "warnings are only returned when asked for" do
+      assert {:ok, _json} = Native.expand(@warn_doc, [])
+    end
+
+    test "warnings are metadata under \"warnings\" on their own" do
+      assert {:ok, _json, metadata} = Native.expand(@warn_doc, [{"collect_warnings", "true"}])
+      assert %{"warnings" => [_ | _]} = metadata = Jason.decode!(metadata)
+      assert Map.keys(metadata) == ["warnings"]
+    end
+
+    test "warnings join other metadata" do
+      opts = [{"collect_warnings", "true"}, {"relabel_bnodes", "true"}]
+      assert {:ok, _json, metadata} = Native.expand(@warn_doc, opts)
+      assert %{"bnode_map" => _, "warnings" => [_ | _]} = Jason.decode!(metadata)
+    end
+  end
+
+  describe "project" do
+    @people Jason.encode!(%{
+              "@context" => %{"@vocab" => "http://schema.org/"},
+              "@graph" => [
+                %{
+                  "@id" => "http://example.org/alice",
+                  "@type" => "Person",
+                  "name" => "Alice",
+                  "email" => "alice@example.org",
+                  "knows" => %{"@id" => "http://example.org/bob", "@type" => "Person", "name" => "Bob"}
+                },
+                %{"@id" => "http://example.org/acme", "@type" => "Organization", "name" => "ACME"}
+              ]
+            })
+
+    @projection Jason.encode!(%{
+                  "properties" => ["@id", "schema:name", "http://schema.org/email", "schema:knows"],
+                  "type" => "schema:Person"
+                })
+
+    test "plucks the requested fields of nodes of the given type" do
+      assert {:ok, json} = Native.project(@people, @projection, [])
+
+      assert Jason.decode!(json) == [
+               %{
+                 "@id" => "http://example.org/alice",
+                 "schema:name" => "Alice",
+                 "http://schema.org/email" => "alice@example.org",
+                 "schema:knows" => "http://example.org/bob"
+               },
+               %{"@id" => "http://example.org/bob", "schema:name" => "Bob"}
+             ]
+    end
+
+    test "works against an open document session" do
+      assert {:ok, session} = Native.document_open(@people)
+      assert {:ok, from_string} = Native.project(@people, @projection, [])
+      assert {:ok, ^from_string} = Native.project(session, @projection, [])
+
+      names = Jason.encode!(%{"properties" => ["n"], "@context" => %{"n" => "http://schema.org/name"}})
+      assert {:ok, json} = Native.project(session, names, [])
+      assert Enum.map(Jason.decode!(json), & &1["n"]) == ["Alice", "Bob", "ACME"]
+    end
+
+    test "rejects a projection without properties" do
+      assert {:error, {:invalid_projection, _}} = Native.project(@people, ~s({"type":"schema:Person"}), [])
+    end
+  end
+
+  describe "diff_structural ignore_paths" do
+    test "changes only under ignored paths produce an empty diff" do
+      old = %{
+        "name" => "Widget",
+        "updated_at" => "2024-01-01",
+        "meta" => %{"rev" => "1-abc", "owner" => "ops"},
+        "items" => [%{"sku" => "a", "timestamp" => 1}, %{"sku" => "b", "timestamp" => 2}]
+      }
+
+      new = %{
+        "name" => "Widget",
+        "updated_at" => "2024-06-01",
+        "meta" => %{"rev" => "2-def", "owner" => "ops"},
+        "items" => [%{"sku" => "a", "timestamp" => 10}, %{"sku" => "b", "timestamp" => 20}]
+      }
+
+      opts = [{"ignore_paths", "updated_at,meta.rev,items.*.timestamp"}]
+      assert {:ok, diff} = Native.diff_structural(Jason.encode!(old), Jason.encode!(new), opts)
+      assert Jason.decode!(diff) == %{}
+
+      assert {:ok, diff} = Native.diff_structural(Jason.encode!(old), Jason.encode!(new), [])
+      assert Map.has_key?(Jason.decode!(diff), "updated_at")
+    end
+
+    test "other changes are still reported" do
+      old = %{"meta" => %{"rev" => "1", "owner" => "ops"}, "tags" => ["a", "b"]}
+      new = %{"meta" => %{"rev" => "2", "owner" => "dev"}, "tags" => ["x", "c"]}
+
+      opts = [{"ignore_paths", "meta[\"rev\"],tags[0]"}]
+      assert {:ok, diff} = Native.diff_structural(Jason.encode!(old), Jason.encode!(new), opts)
+      # an ignored index leaves the indices after it as they were
+      assert Jason.decode!(diff) == %{"meta" => %{"owner" => ["ops", "dev"]}, "tags" => %{"_1" => ["b", "c"]}}
+
+      assert {:ok, patched} = Native.patch_structural(Jason.encode!(old), diff, [])
+      assert Jason.decode!(patched) == %{"meta" => %{"rev" => "1", "owner" => "dev"}, "tags" => ["a", "c"]}
+    end
+  end
+
+  describe "diff_structural null values" do
+    test "changing a value to null is a change that keeps the key" do
+      old = Jason.encode!(%{"name" => "Widget", "owner" => "ops"})
+      new = Jason.encode!(%{"name" => "Widget", "owner" => nil})
+
+      assert {:ok, diff} = Native.diff_structural(old, new, [])
+      assert Jason.decode!(diff) == %{"owner" => ["ops", nil]}
+
+      assert {:ok, patched} = Native.patch_structural(old, diff, [{"strict", "true"}])
+      assert Jason.decode!(patched) == %{"name" => "Widget", "owner" => nil}
+    end
+
+    test "removing a null-valued key is a delete" do
+      old = Jason.encode!(%{"name" => "Widget", "owner" => nil})
+      new = Jason.encode!(%{"name" => "Widget"})
+
+      assert {:ok, diff} = Native.diff_structural(old, new, [])
+      assert Jason.decode!(diff) == %{"owner" => [nil, 0, 0]}
+
+      assert {:ok, patched} = Native.patch_structural(old, diff, [{"strict", "true"}])
+      assert Jason.decode!(patched) == %{"name" => "Widget"}
+
+      # A missing key doesn't hold the null the delete was computed against
+      assert {:error, {:patch_conflict, ["/owner"]}} = Native.patch_structural(new, diff, [{"strict", "true"}])
+    end
+  end
+
+  describe "compact include_context" do
+    @plain_expanded Jason.encode!([
+                      %{"@id" => "http://example.org/a", "http://schema.org/name" => [%{"@value" => "A"}]}
+                    ])
+
+    test "an empty context leaves @context out of the output" do
+      for context <- ["{}", "null", "[]", ~s({"@context": {}})] do
+        {:ok, json} = Native.compact(@plain_expanded, context, [])
+        compacted = Jason.decode!(json)
+        refute Map.has_key?(compacted, "@context")
+        assert compacted["http://schema.org/name"] == "A"
+      end
+    end
+
+    test "always and never override the default" do
+      {:ok, json} = Native.compact(@plain_expanded, "{}", [{"include_context", "always"}])
+      assert Jason.decode!(json)["@context"] == %{}
+
+      context = ~s({"@vocab": "http://schema.org/"})
+      {:ok, json} = Native.compact(@plain_expanded, context, [])
+      assert Jason.decode!(json)["@context"] == %{"@vocab" => "http://schema.org/"}
+
+      {:ok, json} = Native.compact(@plain_expanded, context, [{"include_context", "never"}, {"safe", "true"}])
+      assert Jason.decode!(json) == %{"@id" => "http://example.org/a", "http://schema.org/name" => "A"}
+    end
+  end
+
+  describe "compact context keywords" do
+    @keyword_context ~s({"@version": 1.1, "@protected": true, "@propagate": false, "name": "http://schema.org/name"})
+    @named_expanded ~s([{"@id": "http://example.org/a", "http://schema.org/name": [{"@value": "A"}]}])
+
+    test "@version, @protected and @propagate survive into the output context" do
+      for opts <- [[], [{"safe", "true"}]] do
+        {:ok, json} = Native.compact(@named_expanded, @keyword_context, opts)
+        compacted = Jason.decode!(json)
+
+        assert compacted["@context"]["@version"] == 1.1
+        assert compacted["@context"]["@protected"] == true
+        assert compacted["@context"]["@propagate"] == false
+        assert compacted["name"] == "A"
+      end
+    end
+
+    test "a processor's compaction keeps them too" do
+      {:ok, processor} = Native.processor_new(@keyword_context, [])
+      {:ok, json} = Native.processor_compact(processor, @named_expanded)
+
+      assert %{"@version" => 1.1, "@protected" => true} = Jason.decode!(json)["@context"]
+    end
+  end
+
+  describe "compact safe mode" do
+    # "knows" redefines "name" for the nodes nested under it, so compacting
+    # Bob's ex:name to "name" would read back as ex:nickname
+    @lossy_context %{
+      "ex" => "http://example.org/",
+      "name" => "ex:name",
+      "knows" => %{"@id" => "ex:knows", "@context" => %{"name" => "ex:nickname"}}
+    }
+
+    @lossy_input [
+      %{
+        "@id" => "http://example.org/alice",
+        "http://example.org/name" => [%{"@value" => "Alice"}],
+        "http://example.org/knows" => [
+          %{"@id" => "http://example.org/bob", "http://example.org/name" => [%{"@value" => "Bob"}]}
+        ]
+      }
+    ]
+
+    test "lossy output is rejected with the affected paths" do
+      assert {:error, {:lossy_compaction, paths}} =
+               Native.compact(Jason.encode!(@lossy_input), Jason.encode!(@lossy_context), [{"safe", "true"}])
+
+      assert "/0/http:~1~1example.org~1knows/0/http:~1~1example.org~1name" in paths
+      refute Enum.any?(paths, &(&1 == "/0/http:~1~1example.org~1name"))
+    end
+
+    test "default mode produces the documented lossy output" do
+      assert {:ok, json} = Native.compact(Jason.encode!(@lossy_input), Jason.encode!(@lossy_context), [])
+      compacted = Jason.decode!(json)
+      assert compacted["knows"]["name"] == "Bob"
+
+      {:ok, expanded} = Native.expand(json, [])
+      [node] = Jason.decode!(expanded)
+      assert node["http://example.org/knows"]["http://example.org/nickname"] == %{"@value" => "Bob"}
+    end
+
+    test "faithful compaction passes safe mode unchanged" do
+      input = [%{"@id" => "http://example.org/alice", "http://example.org/name" => [%{"@value" => "Alice", "@language" => "en"}]}]
+      context = %{"ex" => "http://example.org/", "name" => %{"@id" => "ex:name", "@language" => "fr"}}
+
+      assert {:ok, safe} = Native.compact(Jason.encode!(input), Jason.encode!(context), [{"safe", "true"}])
+      assert {:ok, ^safe} = Native.compact(Jason.encode!(input), Jason.encode!(context), [])
+    end
+  end
+
+  describe "datatype coercion in compaction" do
+    @coerced_context %{
+      "ex" => "http://example.org/",
+      "age" => %{"@id" => "ex:age", "@type" => "xsd:integer"},
+      "ageText" => "ex:age"
+    }
+
+    defp coerce_round_trip(doc) do
+      {:ok, expanded} = Native.expand(Jason.encode!(Map.put(doc, "@context", @coerced_context)), [])
+      {:ok, json} = Native.compact(expanded, Jason.encode!(@coerced_context), [{"safe", "true"}])
+      json |> Jason.decode!() |> Map.delete("@context")
+    end
+
+    test "typed value objects compact to scalars under a compact-IRI datatype" do
+      assert coerce_round_trip(%{"age" => "5"}) == %{"age" => "5"}
+      assert coerce_round_trip(%{"age" => 5}) == %{"age" => 5}
+    end
+
+    test "untyped values for the same IRI pick the uncoerced term" do
+      assert coerce_round_trip(%{"age" => [5, "6"], "ageText" => "seven"}) ==
+               %{"age" => [5, "6"], "ageText" => "seven"}
+    end
+  end
+
+  describe "registered remote contexts" do
+    test "a cached context is dereferenced by IRI, relative to @base" do
+      ctx = Jason.encode!(%{"@context" => %{"name" => "http://schema.org/name"}})
+      {:ok, _} = Native.cache_context(ctx, "https://contexts.example.com/people.jsonld")
+
+      for reference <- [
+            "https://contexts.example.com/people.jsonld",
+            [%{"@base" => "https://contexts.example.com/v1/"}, "../people.jsonld"]
+          ] do
+        doc = Jason.encode!(%{"@context" => reference, "name" => "Alice"})
+        {:ok, expanded} = Native.expand(doc, [])
+        assert [%{"http://schema.org/name" => _}] = List.wrap(Jason.decode!(expanded))
+      end
+    end
+
+    test "a context that includes itself is rejected" do
+      iri = "https://contexts.example.com/loop.jsonld"
+      {:ok, _} = Native.cache_context(Jason.encode!(%{"@context" => iri}), iri)
+
+      assert {:error, {:recursive_context_inclusion, _}} =
+               Native.expand(Jason.encode!(%{"@context" => iri, "name" => "Alice"}), [])
+    end
+  end
+
+  describe "from_rdf" do
+    @ex "http://example.com/"
+
+    test "named graphs round trip through to_rdf as nested @graph objects" do
+      doc = %{
+        "@context" => %{"ex" => @ex},
+        "@graph" => [
+          %{"@id" => "ex:alice", "ex:name" => "Alice"},
+          %{"@id" => "ex:g1", "@graph" => [%{"@id" => "ex:bob", "@type" => "ex:Person", "ex:knows" => %{"@id" => "ex:alice"}}]},
+          %{"@id" => "ex:g2", "ex:label" => "second", "@graph" => [%{"@id" => "ex:carol", "ex:name" => "Carol"}]}
+        ]
+      }
+
+      {:ok, nquads} = Native.to_rdf(Jason.encode!(doc), [])
+      {:ok, json} = Native.from_rdf(nquads, [])
+      by_id = json |> Jason.decode!() |> Map.new(&{&1["@id"], &1})
+
+      assert Map.keys(by_id) |> Enum.sort() == [@ex <> "alice", @ex <> "g1", @ex <> "g2"]
+      assert by_id[@ex <> "alice"][@ex <> "name"] == [%{"@value" => "Alice"}]
+
+      assert by_id[@ex <> "g1"]["@graph"] == [
+               %{"@id" => @ex <> "bob", "@type" => [@ex <> "Person"], @ex <> "knows" => [%{"@id" => @ex <> "alice"}]}
+             ]
+
+      assert by_id[@ex <> "g2"][@ex <> "label"] == [%{"@value" => "second"}]
+      assert by_id[@ex <> "g2"]["@graph"] == [%{"@id" => @ex <> "carol", @ex <> "name" => [%{"@value" => "Carol"}]}]
+    end
+
+    test "rdf:first/rest chains fold into @list" do
+      rdf = "http://www.w3.org/1999/02/22-rdf-syntax-ns#"
+
+      nquads = """
+      <#{@ex}s> <#{@ex}p> _:l1 .
+      _:l1 <#{rdf}first> "a" .
+      _:l1 <#{rdf}rest> _:l2 .
+      _:l2 <#{rdf}first> "b"@en .
+      _:l2 <#{rdf}rest> <#{rdf}nil> .
+      """
+
+      {:ok, json} = Native.from_rdf(nquads, [])
+
+      assert Jason.decode!(json) == [
+               %{"@id" => @ex <> "s", @ex <> "p" => [%{"@list" => [%{"@value" => "a"}, %{"@value" => "b", "@language" => "en"}]}]}
+             ]
+    end
+
+    test "malformed N-Quads report the line" do
+      assert {:error, {:invalid_nquads, message}} = Native.from_rdf("<#{@ex}s> <#{@ex}p> \"x\" .\n<#{@ex}s> <#{@ex}p> junk .\n", [])
+      assert message =~ "line 2"
+    end
+
+    test "literals with quotes, newlines and non-BMP characters round trip" do
+      for text <- [~s(she said "hi" \\o/), "first line\nsecond line\r\n\ttabbed", "bell \a and emoji 😀"] do
+        {:ok, nquads} = Native.to_rdf(Jason.encode!(%{"@id" => @ex <> "s", @ex <> "p" => text}), [])
+
+        assert [line] = String.split(nquads, "\n", trim: true)
+        refute String.contains?(line, ["\r", "\t", "\a"])
+        assert {:ok, json} = Native.from_rdf(nquads, [])
+        assert [%{@ex <> "p" => [%{"@value" => ^text}]}] = Jason.decode!(json)
+      end
+    end
+
+    test "control characters use canonical escapes" do
+      {:ok, nquads} = Native.to_rdf(Jason.encode!(%{"@id" => @ex <> "s", @ex <> "p" => "a\"b\\c\nd\u0001"}), [])
+
+      assert nquads == ~s(<#{@ex}s> <#{@ex}p> "a\\"b\\\\c\\nd\\u0001" .\n)
+    end
+  end
+
+  describe "vocab and prefixes options" do
+    @overrides [{"vocab", "https://vocab.acme.test/"}, {"prefixes", ~s({"foaf": "http://xmlns.com/foaf/0.1/"})}]
+
+    test "replace the default vocabulary and prefix set for one call" do
+      doc = Jason.encode!(%{"name" => "Alice", "foaf:nick" => "al", "schema:age" => 3})
+
+      for expand <- [&Native.expand/2, &Native.expand_binary/2] do
+        {:ok, json} = expand.(doc, @overrides)
+        [node] = Jason.decode!(json)
+        assert Map.has_key?(node, "https://vocab.acme.test/name")
+        assert Map.has_key?(node, "http://xmlns.com/foaf/0.1/nick")
+        # the override replaces the default prefixes rather than adding to them
+        assert Map.has_key?(node, "schema:age")
+      end
+
+      {:ok, default} = Native.expand(doc, [])
+      assert [%{"http://example.org/name" => _}] = Jason.decode!(default)
+    end
+
+    test "an inline @context still wins" do
+      doc = Jason.encode!(%{"@context" => %{"@vocab" => "http://doc.test/"}, "name" => "Alice"})
+      {:ok, json} = Native.expand(doc, @overrides)
+      assert [%{"http://doc.test/name" => _}] = Jason.decode!(json)
+    end
+
+    test "to_rdf uses the overridden vocabulary" do
+      doc = Jason.encode!(%{"@id" => "http://example.com/a", "name" => "Alice"})
+      {:ok, nquads} = Native.to_rdf(doc, @overrides)
+      assert nquads =~ "<http://example.com/a> <https://vocab.acme.test/name> \"Alice\" ."
+    end
+
+    test "the vocab must be an absolute IRI" do
+      assert {:error, {:invalid_vocab_mapping, _}} = Native.expand(~s({"name": "A"}), [{"vocab", "relative/"}])
+      assert {:error, {:invalid_prefixes, _}} = Native.expand(~s({"name": "A"}), [{"prefixes", "[1]"}])
+    end
+
+    test "prefixes that don't end in a gen-delim warn, or fail in strict mode" do
+      opts = [{"prefixes", ~s({"ex": "http://ex.test/ns"})}]
+      doc = ~s({"ex:name": "A"})
+
+      assert {:ok, _, metadata} = Native.expand(doc, [{"collect_warnings", "true"} | opts])
+      assert [%{"code" => "prefix_without_gen_delim"}] = Jason.decode!(metadata)["warnings"]
+      assert {:error, {:prefix_without_gen_delim, _}} = Native.expand(doc, [{"strict", "true"} | opts])
+    end
+  end
+
+  describe "already-expanded fast path" do
+    defp expanded_nodes(count) do
+      for i <- 1..count do
+        %{
+          "@id" => "http://example.com/n#{i}",
+          "@type" => ["http://example.com/Thing"],
+          "http://example.com/name" => [%{"@value" => "node #{i}", "@language" => "en"}],
+          "http://example.com/next" => [%{"@id" => "http://example.com/n#{i + 1}"}]
+        }
+      end
+    end
+
+    defp passthroughs do
+      {:ok, stats} = Native.processing_stats()
+      Jason.decode!(stats)["expanded_passthroughs"]
+    end
+
+    test "expanded input passes through unchanged, array-wrapped" do
+      [node] = expanded_nodes(1)
+
+      assert {:ok, json} = Native.expand(Jason.encode!(node), [])
+      assert Jason.decode!(json) == [node]
+      assert {:ok, binary} = Native.expand_binary(Jason.encode!([node]), [])
+      assert Jason.decode!(binary) == [node]
+    end
+
+    test "a top-level array needing expansion comes out as one flat array of nodes" do
+      doc = Jason.encode!([%{"@id" => "http://example.com/a", "name" => "A"}, %{"@id" => "http://example.com/b", "name" => "B"}])
+
+      for expand <- [&Native.expand/2, &Native.expand_binary/2] do
+        {:ok, json} = expand.(doc, [])
+        assert [%{"@id" => "http://example.com/a"}, %{"@id" => "http://example.com/b"}] = Jason.decode!(json)
+      end
+    end
+
+    test "expanded input takes the pass-through and gives the same nodes as full expansion" do
+      nodes = expanded_nodes(50)
+      # Same nodes, but the @context forces full expansion
+      with_context = Jason.encode!(%{"@context" => %{}, "@graph" => nodes})
+
+      before = passthroughs()
+      {:ok, fast} = Native.expand(Jason.encode!(nodes), [])
+      assert passthroughs() > before
+
+      {:ok, full} = Native.expand(with_context, [])
+      assert Jason.decode!(fast) == nodes
+      assert Jason.decode!(full) == nodes
+    end
+
+    test "compact keys or bare values still get full expansion" do
+      {:ok, json} = Native.expand(~s({"@id": "http://example.com/a", "schema:name": [{"@value": "A"}]}), [])
+      assert [%{"http://schema.org/name" => _}] = Jason.decode!(json)
+
+      {:ok, json} = Native.expand(~s({"@id": "http://example.com/a", "http://example.com/p": "bare"}), [])
+      assert [%{"http://example.com/p" => %{"@value" => "bare"}}] = Jason.decode!(json)
+    end
+  end
+
+  describe "incoming_references" do
+    @referencing_doc Jason.encode!(%{
+      "@context" => %{"ex" => "http://example.com/", "members" => %{"@id" => "ex:members", "@container" => "@list"}},
+      "@graph" => [
+        %{"@id" => "ex:alice", "ex:knows" => %{"@id" => "ex:bob"}},
+        %{"@id" => "ex:team", "members" => ["x", %{"@id" => "ex:carol"}, %{"@id" => "ex:bob"}]},
+        %{"@id" => "ex:g", "@graph" => [%{"@id" => "ex:dave", "ex:manager" => %{"@id" => "ex:bob"}}]}
+      ]
+    })
+
+    test "finds direct, list and named-graph references" do
+      {:ok, json} = Native.incoming_references(@referencing_doc, "http://example.com/bob")
+
+      assert Enum.sort_by(Jason.decode!(json), & &1["referencing_node"]) == [
+               %{
+                 "referencing_node" => "http://example.com/alice",
+                 "graph" => "@default",
+                 "property" => "http://example.com/knows",
+                 "path" => "/http:~1~1example.com~1knows/0"
+               },
+               %{
+                 "referencing_node" => "http://example.com/dave",
+                 "graph" => "http://example.com/g",
+                 "property" => "http://example.com/manager",
+                 "path" => "/http:~1~1example.com~1manager/0"
+               },
+               %{
+                 "referencing_node" => "http://example.com/team",
+                 "graph" => "@default",
+                 "property" => "http://example.com/members",
+                 "path" => "/http:~1~1example.com~1members/0/@list/2"
+               }
+             ]
+    end
+
+    test "reuses a session handle and reports unreferenced nodes as empty" do
+      {:ok, session} = Native.document_open(@referencing_doc)
+
+      assert {:ok, json} = Native.incoming_references(session, "http://example.com/carol")
+      assert [%{"referencing_node" => "http://example.com/team"}] = Jason.decode!(json)
+      assert {:ok, "[]"} = Native.incoming_references(session, "http://example.com/alice")
+    end
+  end
+
+  describe "compact embed option" do
+    @embed_context %{"ex" => "http://example.com/", "knows" => %{"@id" => "ex:knows", "@type" => "@id"}}
+
+    @embed_input Jason.encode!(%{
+      "@context" => @embed_context,
+      "@graph" => [
+        %{"@id" => "ex:b", "ex:name" => "B"},
+        %{"@id" => "ex:a", "knows" => "ex:b"}
+      ]
+    })
+
+    defp compact_without_context(opts) do
+      {:ok, json} = Native.compact(@embed_input, Jason.encode!(@embed_context), opts)
+      json |> Jason.decode!() |> Map.delete("@context")
+    end
+
+    test "never (the default) keeps references" do
+      referenced = %{"@graph" => [%{"@id" => "ex:b", "http://example.com/name" => "B"}, %{"@id" => "ex:a", "knows" => "ex:b"}]}
+
+      assert compact_without_context([]) == referenced
+      assert compact_without_context([{"embed", "never"}]) == referenced
+    end
+
+    test "once inlines the referenced node and drops it from the top level" do
+      assert compact_without_context([{"embed", "once"}]) ==
+               %{"@id" => "ex:a", "knows" => %{"@id" => "ex:b", "http://example.com/name" => "B"}}
+    end
+
+    test "once keeps a reference where embedding would be circular, and passes safe mode" do
+      input = Jason.encode!(%{
+        "@context" => @embed_context,
+        "@graph" => [%{"@id" => "ex:a", "knows" => "ex:b"}, %{"@id" => "ex:b", "knows" => "ex:a"}]
+      })
+
+      {:ok, json} = Native.compact(input, Jason.encode!(@embed_context), [{"embed", "once"}, {"safe", "true"}])
+
+      assert Map.delete(Jason.decode!(json), "@context") ==
+               %{"@id" => "ex:a", "knows" => %{"@id" => "ex:b", "knows" => "ex:a"}}
+    end
+  end
+
+  describe "context_coverage" do
+    @coverage_context Jason.encode!(%{
+      "@context" => %{
+        "schema" => "http://schema.org/",
+        "name" => "schema:name",
+        "knows" => %{"@id" => "schema:knows", "@type" => "@id"},
+        "Person" => "schema:Person",
+        "unused" => "schema:unused",
+        "id" => "@id",
+        "secret" => nil
+      }
+    })
+
+    defp coverage(document) do
+      {:ok, json} = Native.context_coverage(Jason.encode!(document), @coverage_context)
+      Jason.decode!(json)
+    end
+
+    test "counts used terms with example paths and lists the unused ones" do
+      report = coverage(%{"id" => "http://ex/a", "@type" => "Person", "name" => "A", "knows" => %{"name" => "B"}})
+
+      assert report["terms"]["name"] == %{"count" => 2, "paths" => ["/knows/name", "/name"]}
+      assert report["terms"]["Person"]["paths"] == ["/@type/0"]
+      assert report["terms"]["id"]["count"] == 1
+      assert report["unused_terms"] == ["schema", "secret", "unused"]
+    end
+
+    test "reports vocab fallbacks, dropped keys and IRIs without a compact form" do
+      report = coverage(%{"name" => "A", "nickname" => "aa", "secret" => "x", "http://other.org/p" => "v"})
+
+      assert report["vocab_fallback"] == %{
+               "nickname" => %{"count" => 1, "iri" => "http://example.org/nickname", "paths" => ["/nickname"]}
+             }
+
+      assert report["dropped"] == %{"secret" => %{"count" => 1, "paths" => ["/secret"]}}
+      assert Map.keys(report["uncompactable_iris"]) == ["http://example.org/nickname", "http://other.org/p"]
+      refute "secret" in report["unused_terms"]
+    end
+
+    test "compact IRIs count toward their prefix term" do
+      report = coverage(%{"schema:age" => "3"})
+
+      assert report["terms"]["schema"] == %{"count" => 1, "paths" => ["/schema:age"]}
+      assert report["uncompactable_iris"] == %{}
+    end
+
+    test "rejects invalid JSON" do
+      assert {:error, _} = Native.context_coverage("{", @coverage_context)
+    end
+  end
+
+  describe "validate_document with a vocabulary" do
+    @vocabulary Jason.encode!(%{
+      "@context" => %{"@vocab" => "http://schema.org/"},
+      "properties" => %{"birthDate" => "Person", "foundingDate" => ["Organization"]},
+      "classes" => %{"Student" => "Person"}
+    })
+
+    defp validate_typed(type, properties) do
+      document = Map.merge(%{"@context" => %{"@vocab" => "http://schema.org/"}, "@type" => type}, properties)
+      Native.validate_document(Jason.encode!(document), [{"vocabulary", @vocabulary}])
+    end
+
+    test "flags a property used on a type that doesn't declare it" do
+      assert {:error, [message]} = validate_typed("Organization", %{"birthDate" => "2000-01-01"})
+      assert message =~ "http://schema.org/birthDate"
+      assert message =~ "http://schema.org/Organization"
+    end
+
+    test "accepts properties on their domain and on subclasses of it" do
+      assert :ok = validate_typed("Person", %{"birthDate" => "2000-01-01"})
+      assert :ok = validate_typed("Student", %{"birthDate" => "2000-01-01"})
+      assert :ok = validate_typed("Organization", %{"foundingDate" => "1990", "name" => "Acme"})
+    end
+
+    test "checks nested nodes" do
+      assert {:error, [message]} =
+               validate_typed("Person", %{"knows" => %{"@type" => "Person", "foundingDate" => "1990"}})
+
+      assert message =~ "foundingDate"
+    end
+
+    test "rejects a malformed vocabulary description" do
+      assert {:error, {:invalid_vocabulary, _}} =
+               Native.validate_document(~s({"@context": {}, "@id": "ex:a"}), [{"vocabulary", "[]"}])
+    end
+  end
+
+  describe "validate_document dangling references" do
+    @dangling_doc %{
+      "@context" => %{"@vocab" => "http://schema.org/", "knows" => %{"@type" => "@id"}},
+      "@id" => "http://example.org/alice",
+      "name" => "Alice",
+      "knows" => [%{"@id" => "_:bob", "name" => "Bob"}, "_:ghost", "http://example.org/elsewhere"]
+    }
+
+    test "flags a reference to an undefined local node" do
+      assert {:error, messages} =
+               Native.validate_document(Jason.encode!(@dangling_doc), [{"dangling_references", "true"}])
+
+      assert [ghost, elsewhere] = messages
+      assert ghost =~ "undefined node _:ghost from http://example.org/alice"
+      assert elsewhere =~ "http://example.org/elsewhere"
+    end
+
+    test "local mode assumes absolute IRIs are defined elsewhere" do
+      assert {:error, [message]} =
+               Native.validate_document(Jason.encode!(@dangling_doc), [{"dangling_references", "local"}])
+
+      assert message =~ "_:ghost"
+
+      defined = Map.put(@dangling_doc, "knows", [%{"@id" => "_:bob", "name" => "Bob"}, "_:bob"])
+      assert :ok = Native.validate_document(Jason.encode!(defined), [{"dangling_references", "true"}])
+    end
+  end
+
+  describe "normalize_types" do
+    @typed_node %{
+      "@context" => %{"type" => "@type"},
+      "@id" => "http://example.com/a",
+      "type" => ["schema:Person", "schema:Agent", "schema:Person"]
+    }
+
+    test "deduplicates and sorts aliased @type values by expanded IRI" do
+      {:ok, json} = Native.expand(Jason.encode!(@typed_node), [{"normalize_types", "true"}])
+
+      assert [%{"@type" => ["http://schema.org/Agent", "http://schema.org/Person"]}] = Jason.decode!(json)
+    end
+
+    test "is off by default" do
+      {:ok, json} = Native.expand(Jason.encode!(@typed_node), [])
+
+      assert [%{"@type" => ["http://schema.org/Person", "http://schema.org/Agent", "http://schema.org/Person"]}] =
+               Jason.decode!(json)
+    end
+
+    test "applies to flattened nodes and leaves one remaining type compactable to a string" do
+      doc = %{"@id" => "http://example.com/a", "@type" => ["schema:Person", "schema:Person"]}
+
+      {:ok, json} = Native.flatten(Jason.encode!(doc), nil, [{"normalize_types", "true"}])
+      assert %{"@graph" => [%{"@type" => ["http://schema.org/Person"]}]} = Jason.decode!(json)
+
+      {:ok, json} = Native.compact(json, ~s({"schema": "http://schema.org/"}), [])
+      assert %{"@type" => "schema:Person"} = Jason.decode!(json)
+    end
+
+    test "diff_semantic treats @type as a set" do
+      old = Jason.encode!(%{"@id" => "http://example.com/a", "@type" => ["Person", "Agent"]})
+      new = Jason.encode!(%{"@id" => "http://example.com/a", "@type" => ["Agent", "Person", "Person"]})
+
+      {:ok, json} = Native.diff_semantic(old, new, [])
+
+      assert %{"added_triples" => [], "removed_triples" => [], "metadata" => %{"semantic_equivalence" => true}} =
+               Jason.decode!(json)
+    end
+  end
+
+  describe "@index and @set containers" do
+    defp expand_first(doc) do
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+      json |> Jason.decode!() |> hd()
+    end
+
+    test "a combined @index/@set container expands an index map into arrays carrying @index" do
+      doc = %{
+        "@context" => %{"post" => %{"@id" => "http://example.com/post", "@container" => ["@index", "@set"]}},
+        "@id" => "http://example.com/blog",
+        "post" => %{
+          "en" => %{"@id" => "http://example.com/p1"},
+          "de" => ["Hallo", %{"@id" => "http://example.com/p2"}]
+        }
+      }
+
+      assert expand_first(doc)["http://example.com/post"] == [
+               %{"@index" => "de", "@value" => "Hallo"},
+               %{"@id" => "http://example.com/p2", "@index" => "de"},
+               %{"@id" => "http://example.com/p1", "@index" => "en"}
+             ]
+    end
+
+    test "@none entries carry no index" do
+      doc = %{
+        "@context" => %{"post" => %{"@id" => "http://example.com/post", "@container" => "@index"}},
+        "post" => %{"@none" => "untagged"}
+      }
+
+      assert expand_first(doc)["http://example.com/post"] == [%{"@value" => "untagged"}]
+    end
+
+    test "a property-valued index becomes a value of that property" do
+      doc = %{
+        "@context" => %{
+          "post" => %{"@id" => "http://example.com/post", "@container" => "@index", "@index" => "http://example.com/lang"}
+        },
+        "post" => %{"en" => %{"@id" => "http://example.com/p1"}}
+      }
+
+      assert expand_first(doc)["http://example.com/post"] == [
+               %{"@id" => "http://example.com/p1", "http://example.com/lang" => [%{"@value" => "en"}]}
+             ]
+    end
+
+    test "a @set container always yields an array" do
+      doc = %{"@context" => %{"tags" => %{"@id" => "http://example.com/tags", "@container" => "@set"}}, "tags" => "one"}
+
+      assert expand_first(doc)["http://example.com/tags"] == [%{"@value" => "one"}]
+    end
+  end
+
+  describe "compile_document" do
+    @compiled_source Jason.encode!(%{
+      "@context" => %{"schema" => "http://schema.org/", "knows" => %{"@id" => "schema:knows", "@type" => "@id"}},
+      "@graph" => [
+        %{"@id" => "http://example.com/alice", "@type" => "schema:Person", "schema:name" => "Alice", "knows" => "http://example.com/bob"},
+        %{"@id" => "http://example.com/bob", "@type" => "schema:Person", "schema:name" => "Bob"}
+      ]
+    })
+
+    setup do
+      {:ok, compiled} = Native.compile_document(@compiled_source, [])
+      {:ok, compiled: compiled}
+    end
+
+    test "frame and query_nodes give the same output for the handle and the string", %{compiled: compiled} do
+      frame = Jason.encode!(%{"@context" => %{}, "@graph" => %{}})
+      assert {:ok, from_string} = Native.frame(@compiled_source, frame, [])
+      assert {:ok, ^from_string} = Native.frame(compiled, frame, [])
+
+      pattern = Jason.encode!(%{"@type" => "schema:Person"})
+      assert {:ok, from_string} = Native.query_nodes(@compiled_source, pattern, [])
+      assert {:ok, ^from_string} = Native.query_nodes(compiled, pattern, [])
+      assert length(Jason.decode!(from_string)) == 2
+
+      for opts <- [[{"limit", "1"}], [{"max_depth", "0"}], [{"max_depth", "1"}]] do
+        assert {:ok, from_string} = Native.query_nodes(@compiled_source, pattern, opts)
+        assert {:ok, ^from_string} = Native.query_nodes(compiled, pattern, opts)
+      end
+    end
+
+    test "project and incoming_references give the same output for the handle and the string", %{compiled: compiled} do
+      projection = Jason.encode!(%{"properties" => ["@id", "schema:name"], "type" => "schema:Person"})
+      assert {:ok, from_string} = Native.project(@compiled_source, projection, [])
+      assert {:ok, ^from_string} = Native.project(compiled, projection, [])
+
+      assert {:ok, from_string} = Native.incoming_references(@compiled_source, "http://example.com/bob")
+      assert {:ok, ^from_string} = Native.incoming_references(compiled, "http://example.com/bob")
+      assert [%{"referencing_node" => "http://example.com/alice"}] = Jason.decode!(from_string)
+    end
+
+    test "rejects invalid JSON" do
+      assert {:error, _} = Native.compile_document("{", [])
+    end
+  end
+
+  describe "to_rdf stats" do
+    @stats_doc Jason.encode!(%{
+      "@context" => %{"knows" => %{"@id" => "schema:knows"}},
+      "@graph" => [
+        %{"@id" => "http://example.com/a", "schema:name" => "A", "knows" => %{"schema:name" => "B"}},
+        %{"@id" => "http://example.com/g", "@graph" => [%{"@id" => "http://example.com/c", "schema:name" => "C"}]}
+      ]
+    })
+
+    test "returns quad statistics alongside the N-Quads" do
+      {:ok, nquads, stats} = Native.to_rdf(@stats_doc, [{"stats", "true"}])
+
+      assert length(String.split(nquads, "\n", trim: true)) == 4
+
+      assert Jason.decode!(stats) == %{
+               "triples" => 4,
+               "distinct_subjects" => 3,
+               "blank_nodes" => 1,
+               "named_graphs" => 1
+             }
+    end
+
+    test "is off by default" do
+      assert {:ok, nquads} = Native.to_rdf(@stats_doc, [])
+      assert {:ok, ^nquads, _} = Native.to_rdf(@stats_doc, [{"stats", "true"}])
+    end
+  end
+
+  describe "filter_language" do
+    @localized Jason.encode!(%{
+      "@context" => %{
+        "label" => %{"@id" => "http://example.com/label", "@container" => "@language"},
+        "name" => "http://example.com/name",
+        "none" => "@none"
+      },
+      "@id" => "http://example.com/a",
+      "label" => %{"en" => "Hi", "de" => "Hallo", "de-CH" => "Grüezi", "none" => "Hi!"},
+      "name" => [%{"@value" => "A", "@language" => "fr"}, %{"@value" => "B", "@language" => "de-AT"}, "plain"]
+    })
+
+    defp filtered(languages, opts \\ []) do
+      {:ok, json} = Native.filter_language(@localized, languages, opts)
+      json |> Jason.decode!() |> Map.delete("@context")
+    end
+
+    test "drops non-matching tags and keeps untagged values and language maps" do
+      assert filtered(["de"]) == %{
+               "@id" => "http://example.com/a",
+               "label" => %{"de" => "Hallo", "de-ch" => "Grüezi", "none" => "Hi!"},
+               "name" => [%{"@language" => "de-at", "@value" => "B"}, "plain"]
+             }
+    end
+
+    test "single keeps the best match per property, falling back from region to language" do
+      assert filtered(["de-CH"], [{"single", "true"}]) == %{
+               "@id" => "http://example.com/a",
+               "label" => %{"de-ch" => "Grüezi"},
+               "name" => %{"@language" => "de-at", "@value" => "B"}
+             }
+    end
+
+    test "single falls back to untagged values when nothing matches" do
+      assert filtered(["es"], [{"single", "true"}]) == %{
+               "@id" => "http://example.com/a",
+               "label" => %{"none" => "Hi!"},
+               "name" => "plain"
+             }
+    end
+
+    test "the wildcard matches any tag" do
+      assert %{"name" => %{"@language" => "fr"}} = filtered(["*"], [{"single", "true"}])
+    end
+  end
+
+  describe "@direction round trip" do
+    @bidi_context %{
+      "@language" => "ar",
+      "@direction" => "rtl",
+      "name" => "http://example.com/name",
+      "ltrname" => %{"@id" => "http://example.com/name", "@direction" => "ltr"}
+    }
+
+    test "strings take the context and term direction" do
+      doc = %{"@context" => @bidi_context, "name" => "x", "ltrname" => "w"}
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+
+      assert [%{"http://example.com/name" => values}] = Jason.decode!(json)
+      assert %{"@value" => "x", "@language" => "ar", "@direction" => "rtl"} in values
+      assert %{"@value" => "w", "@language" => "ar", "@direction" => "ltr"} in values
+    end
+
+    test "compaction folds values to strings only when language and direction both match" do
+      doc = %{
+        "@context" => @bidi_context,
+        "@id" => "http://example.com/a",
+        "name" => ["x", %{"@value" => "y", "@direction" => "ltr"}, %{"@value" => "z", "@language" => "ar"}],
+        "ltrname" => "w"
+      }
+
+      {:ok, json} = Native.compact(Jason.encode!(doc), Jason.encode!(@bidi_context), [])
+      compacted = Jason.decode!(json)
+
+      assert compacted["name"] == ["x", %{"@language" => "ar", "@value" => "z"}]
+      assert compacted["ltrname"] == ["w", %{"@direction" => "ltr", "@value" => "y"}]
+    end
+
+    test "direction without language survives to_rdf and from_rdf" do
+      doc = %{"@id" => "http://example.com/a", "http://example.com/p" => %{"@value" => "q", "@direction" => "rtl"}}
+
+      {:ok, nquads} = Native.to_rdf(Jason.encode!(doc), [])
+      assert nquads =~ ~s("q"^^<https://www.w3.org/ns/i18n#_rtl>)
+
+      {:ok, json} = Native.from_rdf(nquads, [])
+
+      assert [%{"http://example.com/p" => [%{"@value" => "q", "@direction" => "rtl"}]}] = Jason.decode!(json)
+    end
+
+    test "diff_semantic reports a direction flip as a modified property" do
+      old = Jason.encode!(%{"@id" => "http://example.com/a", "http://example.com/p" => %{"@value" => "q", "@direction" => "rtl"}})
+      new = Jason.encode!(%{"@id" => "http://example.com/a", "http://example.com/p" => %{"@value" => "q", "@direction" => "ltr"}})
+
+      {:ok, json} = Native.diff_semantic(old, new, [])
+      diff = Jason.decode!(json)
+
+      refute diff["metadata"]["semantic_equivalence"]
+      assert [%{"node_id" => "http://example.com/a", "modified_properties" => [change]}] = diff["modified_nodes"]
+      assert change["old_value"]["direction"] == "rtl"
+      assert change["new_value"]["direction"] == "ltr"
+    end
+  end
+
+  describe "diff_semantic N-Quads output" do
+    test "added and removed triples come back as N-Quads that parse to the same triple set" do
+      old = Jason.encode!(%{"@id" => "http://example.com/a", "http://schema.org/name" => "John"})
+
+      new =
+        Jason.encode!(%{
+          "@id" => "http://example.com/a",
+          "http://schema.org/name" => %{"@value" => "Jane", "@language" => "en"},
+          "http://schema.org/age" => 30,
+          "http://schema.org/knows" => %{"@id" => "http://example.com/b"}
+        })
+
+      {:ok, json} = Native.diff_semantic(old, new, [])
+      {:ok, nquads_json} = Native.diff_semantic(old, new, [{"output", "nquads"}])
+      diff = Jason.decode!(json)
+      nquads_diff = Jason.decode!(nquads_json)
+
+      assert Enum.sort(nquads_diff["added_triples"]) == [
+               ~s(<http://example.com/a> <http://schema.org/age> "30"^^<http://www.w3.org/2001/XMLSchema#integer> .),
+               ~s(<http://example.com/a> <http://schema.org/knows> <http://example.com/b> .),
+               ~s(<http://example.com/a> <http://schema.org/name> "Jane"@en .)
+             ]
+
+      assert nquads_diff["removed_triples"] == [~s(<http://example.com/a> <http://schema.org/name> "John" .)]
+      assert length(nquads_diff["added_triples"]) == length(diff["added_triples"])
+
+      {:ok, parsed} = Native.from_rdf(Enum.join(nquads_diff["added_triples"], "\n"), [])
+      [node] = Jason.decode!(parsed)
+
+      assert node["http://schema.org/age"] == [
+               %{"@value" => "30", "@type" => "http://www.w3.org/2001/XMLSchema#integer"}
+             ]
+
+      assert node["http://schema.org/knows"] == [%{"@id" => "http://example.com/b"}]
+      assert node["http://schema.org/name"] == [%{"@value" => "Jane", "@language" => "en"}]
+    end
+  end
+
+  describe "patch_structural strict mode" do
+    @current Jason.encode!(%{"name" => "Jane", "age" => 31, "tags" => ["a", "b"], "meta" => %{"rev" => 2}})
+
+    test "rejects a stale patch, listing the mismatched paths" do
+      stale =
+        Jason.encode!(%{
+          "name" => ["John", "Jim"],
+          "age" => [30, 0, 0],
+          "tags" => %{"_t" => "a", "_1" => ["x", 0, 0]},
+          "meta" => %{"rev" => [2, 3]}
+        })
+
+      assert {:error, {:patch_conflict, paths}} = Native.patch_structural(@current, stale, [{"strict", "true"}])
+      assert Enum.sort(paths) == ["/age", "/name", "/tags/1"]
+
+      # Without strict the same patch overwrites silently
+      assert {:ok, _} = Native.patch_structural(@current, stale, [])
+    end
+
+    test "applies a patch whose old values still match" do
+      fresh =
+        Jason.encode!(%{
+          "name" => ["Jane", "Jim"],
+          "tags" => %{"_t" => "a", "_1" => ["b", 0, 0]},
+          "meta" => %{"rev" => [2, 3]},
+          "city" => ["NYC"]
+        })
+
+      assert {:ok, json} = Native.patch_structural(@current, fresh, [{"strict", "true"}])
+
+      assert Jason.decode!(json) == %{
+               "name" => "Jim",
+               "age" => 31,
+               "tags" => ["a"],
+               "meta" => %{"rev" => 3},
+               "city" => "NYC"
+             }
+    end
+
+    test "an addition conflicts with a different existing value" do
+      patch = Jason.encode!(%{"age" => [40]})
+
+      assert {:error, {:patch_conflict, ["/age"]}} = Native.patch_structural(@current, patch, [{"strict", "true"}])
+    end
+  end
+
+  describe "cache namespaces" do
+    @tenant_ctx "https://contexts.example.com/tenant.jsonld"
+
+    defp expand_name(opts) do
+      doc = Jason.encode!(%{"@context" => @tenant_ctx, "name" => "Alice"})
+      {:ok, expanded} = Native.expand(doc, opts)
+      expanded |> Jason.decode!() |> List.wrap() |> hd() |> Map.keys()
+    end
+
+    test "a context cached in one namespace is invisible to others" do
+      ctx = Jason.encode!(%{"@context" => %{"name" => "http://tenant-a.example/name"}})
+      {:ok, _} = Native.cache_context(ctx, @tenant_ctx, [{"namespace", "isolation-a"}])
+
+      assert expand_name([{"namespace", "isolation-a"}]) == ["http://tenant-a.example/name"]
+      refute expand_name([]) == ["http://tenant-a.example/name"]
+
+      doc = Jason.encode!(%{"@context" => @tenant_ctx, "name" => "Alice"})
+      assert {:error, {:loading_remote_context_failed, reason}} = Native.expand(doc, [{"namespace", "isolation-b"}])
+      assert reason =~ @tenant_ctx
+    end
+
+    test "stats and clearing are scoped to the namespace" do
+      ctx = Jason.encode!(%{"@context" => %{"name" => "http://schema.org/name"}})
+      {:ok, _} = Native.cache_context(ctx, @tenant_ctx, [{"namespace", "scoped-a"}])
+      {:ok, _} = Native.cache_context(ctx, @tenant_ctx, [{"namespace", "scoped-b"}])
+      expand_name([{"namespace", "scoped-a"}])
+
+      {:ok, stats} = Native.context_cache_stats([{"namespace", "scoped-a"}])
+
+      assert %{"namespaces" => %{"scoped-a" => %{"entries" => 1, "hits" => 1, "evictions" => 0}}} =
+               Jason.decode!(stats)
+
+      assert {:ok, 1} = Native.clear_caches([{"namespace", "scoped-a"}])
+
+      {:ok, stats} = Native.context_cache_stats([])
+      namespaces = Jason.decode!(stats)["namespaces"]
+      refute Map.has_key?(namespaces, "scoped-a")
+      assert %{"entries" => 1} = namespaces["scoped-b"]
+    end
+  end
+
+  describe "value object @index in diffs" do
+    @indexed_old %{
+      "@id" => "http://example.com/a",
+      "http://schema.org/name" => %{"@value" => "Alice", "@index" => "v1"},
+      "http://schema.org/alternateName" => [%{"@value" => "Al", "@index" => "v1"}, %{"@value" => "Ally"}]
+    }
+    @indexed_new %{
+      "@id" => "http://example.com/a",
+      "http://schema.org/name" => %{"@value" => "Alice", "@index" => "v2"},
+      "http://schema.org/alternateName" => [%{"@value" => "Al", "@index" => "v2"}, %{"@value" => "Ally"}]
+    }
+
+    test "an index-only change is a minimal structural delta that patches back" do
+      old = Jason.encode!(@indexed_old)
+      {:ok, delta} = Native.diff_structural(old, Jason.encode!(@indexed_new), [])
+
+      assert Jason.decode!(delta) == %{
+               "http://schema.org/name" => %{"@index" => ["v1", "v2"]},
+               "http://schema.org/alternateName" => %{"_0" => %{"@index" => ["v1", "v2"]}}
+             }
+
+      {:ok, patched} = Native.patch_structural(old, delta, [])
+      assert Jason.decode!(patched) == @indexed_new
+    end
+
+    test "an index-only change is a modified property in the semantic diff" do
+      {:ok, json} = Native.diff_semantic(Jason.encode!(@indexed_old), Jason.encode!(@indexed_new), [])
+      diff = Jason.decode!(json)
+
+      assert %{"metadata" => %{"semantic_equivalence" => false}} = diff
+      [%{"node_id" => "http://example.com/a", "modified_properties" => modified}] = diff["modified_nodes"]
+
+      assert %{"old_value" => %{"index" => "v1", "value" => "Alice"}, "new_value" => %{"index" => "v2"}} =
+               Enum.find(modified, &(&1["property"] == "http://schema.org/name"))
+    end
+  end
+
+  describe "did_base expansion" do
+    test "fragment references in a verification method resolve against the DID" do
+      doc = %{
+        "@context" => %{
+          "sec" => "https://w3id.org/security#",
+          "verificationMethod" => %{"@id" => "sec:verificationMethod", "@type" => "@id"},
+          "assertionMethod" => %{"@id" => "sec:assertionMethod", "@type" => "@id"},
+          "controller" => %{"@id" => "sec:controller", "@type" => "@id"}
+        },
+        "@id" => "did:example:123",
+        "verificationMethod" => [%{"@id" => "#key-1", "controller" => "did:example:123"}],
+        "assertionMethod" => "#key-1"
+      }
+
+      {:ok, json} = Native.expand(Jason.encode!(doc), [{"did_base", "did:example:123"}])
+      [node] = Jason.decode!(json)
+
+      assert node["@id"] == "did:example:123"
+      assert [%{"@id" => "did:example:123#key-1"} = method] = node["https://w3id.org/security#verificationMethod"]
+      assert method["https://w3id.org/security#controller"] == %{"@id" => "did:example:123"}
+      assert node["https://w3id.org/security#assertionMethod"] == %{"@id" => "did:example:123#key-1"}
+    end
+  end
+
+  describe "graph addressing in operational patches" do
+    defp named_graph(id, name), do: %{"@id" => id, "@graph" => [%{"@id" => "#{id}/n", "http://schema.org/name" => name}]}
+
+    test "a patch lands in the right graph after the graph array is reordered" do
+      old = %{"@graph" => [named_graph("http://example.com/g1", "A"), named_graph("http://example.com/g2", "B")]}
+      new = %{"@graph" => [named_graph("http://example.com/g1", "A2"), named_graph("http://example.com/g2", "B")]}
+
+      {:ok, patch} = Native.diff_operational(Jason.encode!(old), Jason.encode!(new), [{"addressing", "node"}])
+
+      assert Enum.all?(Jason.decode!(patch)["operations"], fn op ->
+               Enum.at(op["path"], 1) == %{"graph" => "http://example.com/g1"}
+             end)
+
+      reordered = %{"@graph" => [named_graph("http://example.com/g2", "B"), named_graph("http://example.com/g1", "A")]}
+      {:ok, patched} = Native.patch_operational(Jason.encode!(reordered), patch, [])
+
+      assert Jason.decode!(patched) ==
+               %{"@graph" => [named_graph("http://example.com/g2", "B"), named_graph("http://example.com/g1", "A2")]}
+    end
+
+    test "added and removed graphs are addressed by @id" do
+      old = %{"@graph" => [named_graph("http://example.com/g1", "A"), named_graph("http://example.com/g2", "B")]}
+      new = %{"@graph" => [named_graph("http://example.com/g2", "B"), named_graph("http://example.com/g3", "C")]}
+
+      {:ok, patch} = Native.diff_operational(Jason.encode!(old), Jason.encode!(new), [{"addressing", "node"}])
+      {:ok, patched} = Native.patch_operational(Jason.encode!(old), patch, [])
+
+      assert Jason.decode!(patched) == new
+    end
+  end
+
+  describe "number_datatypes" do
+    @number_datatypes [{"number_datatypes", ~s({"float": "xsd:decimal"})}]
+    @xsd "http://www.w3.org/2001/XMLSchema#"
+
+    test "a monetary value round trips through RDF as xsd:decimal" do
+      doc = Jason.encode!(%{"@context" => %{"@vocab" => "http://schema.org/"}, "@id" => "http://example.com/offer", "price" => 19.99})
+
+      {:ok, expanded} = Native.expand(doc, @number_datatypes)
+      assert [%{"http://schema.org/price" => [%{"@value" => 19.99, "@type" => "#{@xsd}decimal"}]}] = Jason.decode!(expanded)
+
+      {:ok, nquads} = Native.to_rdf(doc, @number_datatypes)
+      assert nquads =~ ~s(<http://schema.org/price> "19.99"^^<#{@xsd}decimal> .)
+
+      {:ok, json} = Native.from_rdf(nquads, @number_datatypes)
+      assert [%{"http://schema.org/price" => [%{"@value" => 19.99}]}] = Jason.decode!(json)
+    end
+
+    test "per-term @type coercion takes precedence" do
+      doc = %{
+        "@context" => %{"@vocab" => "http://schema.org/", "weight" => %{"@id" => "http://schema.org/weight", "@type" => "#{@xsd}double"}},
+        "price" => 5.5,
+        "weight" => 1.5,
+        "count" => 3
+      }
+
+      {:ok, expanded} = Native.expand(Jason.encode!(doc), [{"number_datatypes", ~s({"float": "xsd:decimal", "i32": "xsd:int"})}])
+      [node] = Jason.decode!(expanded)
+
+      assert node["http://schema.org/price"] == [%{"@value" => 5.5, "@type" => "#{@xsd}decimal"}]
+      assert node["http://schema.org/weight"] == [%{"@value" => 1.5, "@type" => "#{@xsd}double"}]
+      assert node["http://schema.org/count"] == [%{"@value" => 3, "@type" => "#{@xsd}int"}]
+    end
+
+    test "an invalid mapping is rejected" do
+      doc = ~s({"@context": {"@vocab": "http://schema.org/"}, "price": 1.5})
+
+      assert {:error, {:invalid_number_datatypes, _}} = Native.expand(doc, [{"number_datatypes", ~s({"float": "decimal"})}])
+      assert {:error, {:invalid_number_datatypes, _}} = Native.from_rdf("", [{"number_datatypes", ~s({"money": "xsd:decimal"})}])
+    end
+  end
+
+  describe "@annotation" do
+    @annotated ~s({
+      "@context": {"@vocab": "http://example.com/", "source": {"@id": "http://example.com/source", "@type": "@id"}},
+      "@id": "http://example.com/bob",
+      "age": {"@value": 23, "@annotation": {"certainty": 0.8, "source": "http://example.com/census"}}
+    })
+
+    test "a value object's annotation is expanded under the rdfstar option" do
+      {:ok, expanded} = Native.expand(@annotated, [{"rdfstar", "true"}])
+      [node] = Jason.decode!(expanded)
+
+      assert [%{"@value" => 23, "@annotation" => [annotation]}] = List.wrap(node["http://example.com/age"])
+      assert annotation["http://example.com/source"] |> List.wrap() == [%{"@id" => "http://example.com/census"}]
+      assert [%{"@value" => 0.8}] = List.wrap(annotation["http://example.com/certainty"])
+    end
+
+    test "annotations are rejected without the option or in invalid positions" do
+      assert {:error, {:invalid_annotation, _}} = Native.expand(@annotated, [])
+
+      top_level = ~s({"@id": "http://example.com/a", "@annotation": {"http://example.com/p": 1}})
+      assert {:error, {:invalid_annotation, _}} = Native.expand(top_level, [{"rdfstar", "true"}])
+
+      with_id = ~s({"http://example.com/p": {"@value": 1, "@annotation": {"@id": "http://example.com/x"}}})
+      assert {:error, {:invalid_annotation, _}} = Native.expand(with_id, [{"rdfstar", "true"}])
+    end
+  end
+
+  describe "reject_duplicate_keys" do
+    @smuggled ~s({"@id": "http://example.com/a", "http://schema.org/knows": {"@id": "http://example.com/b", "@id": "http://example.com/evil"}})
+
+    test "binary entry points report the first duplicate key and its object" do
+      opts = [{"reject_duplicate_keys", "true"}]
+
+      assert {:error, {:duplicate_key, "/http:~1~1schema.org~1knows", "@id"}} = Native.expand_binary(@smuggled, opts)
+      assert {:error, {:duplicate_key, "/http:~1~1schema.org~1knows", "@id"}} = Native.compile_document(@smuggled, opts)
+    end
+
+    test "parsing stays permissive by default" do
+      assert {:ok, _} = Native.expand_binary(@smuggled, [])
+      assert {:ok, _} = Native.expand_binary(~s({"@id": "http://example.com/a"}), [{"reject_duplicate_keys", "true"}])
+    end
+  end
+
+  describe "compute_lcs_array delta format" do
+    test "the delta applied to the old array gives the new array" do
+      old = [%{"@id" => "http://example.com/a"}, "x", "y", "z", 5]
+      new = ["z", "x", %{"@id" => "http://example.com/b"}, "y", 5, 6]
+
+      {:ok, delta_json} = Native.compute_lcs_array(Jason.encode!(old), Jason.encode!(new), [{"format", "delta"}])
+      delta = Jason.decode!(delta_json)
+
+      assert delta["_t"] == "a"
+      # "x", "y" and 5 are the common subsequence and stay untouched
+      refute Enum.any?(["_1", "_2", "_4"], &Map.has_key?(delta, &1))
+
+      {:ok, patched} = Native.patch_structural(Jason.encode!(old), delta_json, [])
+      assert Jason.decode!(patched) == new
+    end
+
+    test "ops stay the default and only touch items outside the LCS" do
+      {:ok, ops} = Native.compute_lcs_array(Jason.encode!([1, 2, 3, 4]), Jason.encode!([1, 3, 4, 5]))
+
+      assert Jason.decode!(ops) == [
+               %{"type" => "delete", "index" => 1},
+               %{"type" => "insert", "index" => 3, "value" => 5}
+             ]
+    end
+
+    test "arrays too large for the LCS table only match items at the same index" do
+      old = Enum.map(0..2999, &"a#{&1}")
+      new = Enum.map(0..2999, fn i -> if i == 1500, do: "a1500", else: "b#{i}" end)
+
+      {:ok, delta_json} = Native.compute_lcs_array(Jason.encode!(old), Jason.encode!(new), [{"format", "delta"}])
+      delta = Jason.decode!(delta_json)
+
+      refute Map.has_key?(delta, "_1500") or Map.has_key?(delta, "1500")
+      assert map_size(delta) == 1 + 2 * 2999
+
+      {:ok, patched} = Native.patch_structural(Jason.encode!(old), delta_json, [])
+      assert Jason.decode!(patched) == new
+    end
+  end
+
+  describe "redact" do
+    @pii_doc %{
+      "@context" => %{
+        "schema" => "http://schema.org/",
+        "email" => "schema:email",
+        "mail" => "http://schema.org/email",
+        "name" => %{"@id" => "schema:name", "@container" => "@language"}
+      },
+      "@id" => "http://example.com/alice",
+      "email" => "alice@example.com",
+      "name" => %{"en" => "Alice"},
+      "schema:knows" => %{"@list" => [%{"@id" => "http://example.com/bob", "mail" => "bob@example.com"}]},
+      "@graph" => [%{"@id" => "http://example.com/carol", "http://schema.org/email" => "carol@example.com"}]
+    }
+
+    test "aliased properties are redacted everywhere in the tree" do
+      {:ok, json, report} = Native.redact(Jason.encode!(@pii_doc), ["email", "http://schema.org/name"], [])
+      redacted = Jason.decode!(json)
+      report = Jason.decode!(report)
+
+      assert redacted["email"] == %{"@redacted" => true}
+      assert redacted["name"] == %{"@redacted" => true}
+      assert [%{"mail" => %{"@redacted" => true}}] = redacted["schema:knows"]["@list"]
+      assert [%{"http://schema.org/email" => %{"@redacted" => true}}] = redacted["@graph"]
+
+      assert report["count"] == 4
+      assert report["redactions"] |> Enum.map(& &1["node"]) |> Enum.sort() ==
+               ["http://example.com/alice", "http://example.com/alice", "http://example.com/bob", "http://example.com/carol"]
+      refute json =~ "@example.com"
+    end
+
+    test "remove mode drops the keys" do
+      {:ok, json, report} = Native.redact(Jason.encode!(@pii_doc), ["schema:email"], [{"mode", "remove"}])
+      redacted = Jason.decode!(json)
+
+      refute Map.has_key?(redacted, "email")
+      assert [%{"@id" => "http://example.com/bob"} = bob] = redacted["schema:knows"]["@list"]
+      refute Map.has_key?(bob, "mail")
+      assert redacted["name"] == %{"en" => "Alice"}
+      assert Jason.decode!(report)["count"] == 3
+    end
+  end
+
+  describe "frame max_embed_depth" do
+    @chain %{
+      "@context" => %{"@vocab" => "http://example.com/", "next" => %{"@type" => "@id"}},
+      "@graph" => [
+        %{"@id" => "http://example.com/a", "@type" => "Head", "next" => "http://example.com/b"},
+        %{"@id" => "http://example.com/b", "next" => "http://example.com/c"},
+        %{"@id" => "http://example.com/c", "next" => "http://example.com/d"},
+        %{"@id" => "http://example.com/d", "next" => "http://example.com/e"},
+        %{"@id" => "http://example.com/e"}
+      ]
+    }
+    @chain_frame %{
+      "@context" => %{"@vocab" => "http://example.com/", "next" => %{"@type" => "@id"}},
+      "@type" => "Head",
+      "@embed" => "@always"
+    }
+
+    defp embed_depth(%{"next" => next}), do: 1 + embed_depth(next)
+    defp embed_depth(_reference), do: 0
+
+    test "embedding stops at the configured depth" do
+      {:ok, framed} = Native.frame(Jason.encode!(@chain), Jason.encode!(@chain_frame), [{"max_embed_depth", "2"}])
+      framed = Jason.decode!(framed)
+
+      assert framed["@id"] == "http://example.com/a"
+      assert framed["next"]["@id"] == "http://example.com/b"
+      assert framed["next"]["next"]["@id"] == "http://example.com/c"
+      assert framed["next"]["next"]["next"] == "http://example.com/d"
+      assert embed_depth(framed) == 3
+    end
+
+    test "without the option the whole chain is embedded" do
+      {:ok, framed} = Native.frame(Jason.encode!(@chain), Jason.encode!(@chain_frame), [])
+
+      assert embed_depth(Jason.decode!(framed)) == 4
+    end
+  end
+
+  describe "expand_incremental" do
+    @incremental_context %{
+      "@vocab" => "http://schema.org/",
+      "Book" => %{"@id" => "http://schema.org/Book", "@context" => %{"title" => %{"@id" => "http://schema.org/headline", "@language" => "en"}}},
+      "knows" => %{"@type" => "@id"}
+    }
+
+    defp incremental_source do
+      graph =
+        for i <- 0..19 do
+          %{"@id" => "http://example.com/#{i}", "@type" => "Thing", "name" => "n#{i}", "title" => "t", "knows" => "http://example.com/#{rem(i + 1, 20)}"}
+        end
+
+      %{"@context" => @incremental_context, "@graph" => graph}
+    end
+
+    defp random_edit(doc, round) do
+      i = :rand.uniform(length(doc["@graph"])) - 1
+
+      case :rand.uniform(6) do
+        1 -> update_in(doc, ["@graph", Access.at(i)], &Map.put(&1, "name", "renamed #{round}"))
+        2 -> update_in(doc, ["@graph", Access.at(i)], &Map.put(&1, "@type", ["Thing", "Book"]))
+        3 -> update_in(doc, ["@graph", Access.at(i)], &Map.put(&1, "@context", %{"title" => "http://purl.org/dc/terms/title"}))
+        4 -> update_in(doc, ["@graph", Access.at(i)], &Map.delete(&1, "title"))
+        5 -> put_in(doc, ["@context", "name"], "http://example.com/name#{rem(round, 3)}")
+        6 -> update_in(doc, ["@graph"], &(&1 ++ [%{"@id" => "http://example.com/new#{round}", "name" => "x"}]))
+      end
+    end
+
+    test "matches full re-expansion over random patches" do
+      :rand.seed(:exsss, {9, 5, 2})
+      source = incremental_source()
+      {:ok, session} = Native.document_open(Jason.encode!(source))
+
+      strategies =
+        Enum.reduce(1..60, {source, []}, fn round, {doc, strategies} ->
+          edited = random_edit(doc, round)
+          {:ok, patch} = Native.diff_structural(Jason.encode!(doc), Jason.encode!(edited), [])
+          {:ok, patched} = Native.patch_structural(Jason.encode!(doc), patch, [])
+
+          assert {:ok, stats} = Native.expand_incremental(session, patch, [])
+          assert {:ok, incremental} = Native.document_expanded(session)
+          assert {:ok, full} = Native.expand(patched, [])
+          assert Jason.decode!(incremental) == Jason.decode!(full)
+
+          {Jason.decode!(patched), [Jason.decode!(stats)["strategy"] | strategies]}
+        end)
+        |> elem(1)
+
+      assert "incremental" in strategies
+      assert "full" in strategies
+    end
+
+    test "re-expands only the edited element and keeps the indexes current" do
+      source = incremental_source()
+      {:ok, session} = Native.compile_document(Jason.encode!(source), [])
+      edited = put_in(source, ["@graph", Access.at(3), "knows"], "http://example.com/9")
+      {:ok, patch} = Native.diff_structural(Jason.encode!(source), Jason.encode!(edited), [])
+
+      assert {:ok, stats} = Native.expand_incremental(session, patch, [])
+      assert %{"strategy" => "incremental", "reexpanded" => 1} = Jason.decode!(stats)
+
+      {:ok, references} = Native.incoming_references(session, "http://example.com/9")
+      assert references |> Jason.decode!() |> Enum.map(& &1["referencing_node"]) |> Enum.sort() ==
+               ["http://example.com/3", "http://example.com/8"]
+    end
+
+    test "framing a patched session frames the patched graph" do
+      source = incremental_source()
+      {:ok, session} = Native.compile_document(Jason.encode!(source), [])
+      edited = put_in(source, ["@graph", Access.at(3), "name"], "renamed")
+      {:ok, patch} = Native.diff_structural(Jason.encode!(source), Jason.encode!(edited), [])
+      {:ok, _stats} = Native.expand_incremental(session, patch, [])
+
+      frame = Jason.encode!(%{"@context" => @incremental_context, "@id" => "http://example.com/3"})
+      assert {:ok, from_string} = Native.frame(Jason.encode!(edited), frame, [])
+      assert {:ok, ^from_string} = Native.frame(session, frame, [])
+      assert from_string =~ "renamed"
+    end
+
+    test "querying a patched session queries the patched source" do
+      source = incremental_source()
+      {:ok, session} = Native.compile_document(Jason.encode!(source), [])
+      edited = put_in(source, ["@graph", Access.at(3), "name"], "renamed")
+      {:ok, patch} = Native.diff_structural(Jason.encode!(source), Jason.encode!(edited), [])
+      {:ok, _stats} = Native.expand_incremental(session, patch, [])
+
+      pattern = Jason.encode!(%{"name" => "renamed"})
+      assert {:ok, from_string} = Native.query_nodes(Jason.encode!(edited), pattern, [])
+      assert {:ok, ^from_string} = Native.query_nodes(session, pattern, [])
+      assert [%{"name" => "renamed"}] = Jason.decode!(from_string)
+    end
+
+    test "strict mode rejects a patch that does not fit the document" do
+      {:ok, session} = Native.document_open(Jason.encode!(incremental_source()))
+      patch = Jason.encode!(%{"@graph" => %{"_t" => "a", "_0" => %{"name" => ["not the old name", "new"]}}})
+
+      assert {:error, {:patch_conflict, _}} = Native.expand_incremental(session, patch, [{"strict", "true"}])
+      assert {:ok, expanded} = Native.document_expanded(session)
+      assert expanded =~ "n0"
+    end
+  end
+
+  describe "diff_semantic formats" do
+    @semantic_fixtures "test/fixtures/semantic_diff"
+
+    setup do
+      {:ok, old: File.read!(Path.join(@semantic_fixtures, "old.jsonld")), new: File.read!(Path.join(@semantic_fixtures, "new.jsonld"))}
+    end
+
+    test "rdf-patch matches the fixture", %{old: old, new: new} do
+      assert {:ok, patch} = Native.diff_semantic(old, new, [{"format", "rdf-patch"}])
+      assert patch == File.read!(Path.join(@semantic_fixtures, "expected.rdfp"))
+    end
+
+    test "sparql-update matches the fixture", %{old: old, new: new} do
+      assert {:ok, update} = Native.diff_semantic(old, new, [{"format", "sparql-update"}])
+      assert update == File.read!(Path.join(@semantic_fixtures, "expected.ru"))
+    end
+
+    test "rdf-patch deletes use the labels of the canonical N-Quads", %{old: old, new: new} do
+      {:ok, canonical} = Native.normalize_rdf_graph(old, "urdna2015")
+      {:ok, patch} = Native.diff_semantic(old, new, [{"format", "rdf-patch"}])
+
+      for "D " <> quad <- String.split(patch, "\n") do
+        assert canonical =~ quad
+      end
+    end
+
+    test "identical documents give an empty patch", %{old: old} do
+      assert {:ok, "TX .\nTC .\n"} = Native.diff_semantic(old, old, [{"format", "rdf-patch"}])
+      assert {:ok, ""} = Native.diff_semantic(old, old, [{"format", "sparql-update"}])
+    end
+
+    test "json stays the default", %{old: old, new: new} do
+      assert {:ok, diff} = Native.diff_semantic(old, new, [{"format", "json"}])
+      assert {:ok, ^diff} = Native.diff_semantic(old, new, [])
+      assert %{"added_triples" => _} = Jason.decode!(diff)
+    end
+  end
+
+  describe "number precision" do
+    @big_integer "123456789012345678901234567890"
+    @long_decimal "0.123456789012345678901234567890"
+
+    defp precise_doc do
+      ~s({"@context": {"@vocab": "http://example.com/"}, "@id": "http://example.com/s", "amount": #{@big_integer}, "rate": #{@long_decimal}})
+    end
+
+    test "expansion keeps numbers beyond f64 precision as written" do
+      {:ok, expanded} = Native.expand(precise_doc(), [])
+
+      assert expanded =~ ~s("@value":#{@big_integer})
+      assert expanded =~ ~s("@value":#{@long_decimal})
+      assert [%{"http://example.com/amount" => %{"@value" => 123_456_789_012_345_678_901_234_567_890}}] = Jason.decode!(expanded)
+    end
+
+    test "to_rdf writes the exact lexical form" do
+      {:ok, nquads} = Native.to_rdf(precise_doc(), [{"number_datatypes", ~s({"float": "xsd:decimal"})}])
+
+      assert nquads =~ ~s("#{@big_integer}"^^<http://www.w3.org/2001/XMLSchema#integer>)
+      assert nquads =~ ~s("#{@long_decimal}"^^<http://www.w3.org/2001/XMLSchema#decimal>)
+    end
+
+    test "from_rdf turns the literals back into exact numbers" do
+      opts = [{"number_datatypes", ~s({"float": "xsd:decimal"})}]
+      {:ok, nquads} = Native.to_rdf(precise_doc(), opts)
+      {:ok, json} = Native.from_rdf(nquads, opts)
+
+      assert json =~ ~s("@value":#{@big_integer})
+      assert json =~ ~s("@value":#{@long_decimal})
+    end
+  end
+
+  describe "export_caches and import_caches" do
+    @warm_context_iri "http://example.com/contexts/warm.jsonld"
+
+    defp warm_stats do
+      {:ok, stats} = Native.context_cache_stats([{"namespace", "warm"}])
+      Jason.decode!(stats)["namespaces"]["warm"]
+    end
+
+    test "a restored context is hit without being registered again" do
+      context = Jason.encode!(%{"@context" => %{"name" => "http://schema.org/name"}})
+      {:ok, _} = Native.cache_context(context, @warm_context_iri, [{"namespace", "warm"}])
+      {:ok, snapshot} = Native.export_caches()
+
+      {:ok, _} = Native.clear_caches([{"namespace", "warm"}])
+      assert warm_stats() == nil
+
+      assert {:ok, report} = Native.import_caches(snapshot)
+      assert Jason.decode!(report)["contexts"] >= 1
+      assert %{"entries" => 1, "hits" => 0, "misses" => 0} = warm_stats()
+
+      doc = Jason.encode!(%{"@context" => @warm_context_iri, "name" => "Alice"})
+      {:ok, expanded} = Native.expand(doc, [{"namespace", "warm"}])
+
+      assert [%{"http://schema.org/name" => _}] = Jason.decode!(expanded)
+      assert %{"hits" => hits, "misses" => 0} = warm_stats()
+      assert hits > 0
+    end
+
+    test "incompatible snapshots are refused and leave the caches alone" do
+      {:ok, _} = Native.cache_context(~s({"@vocab": "http://example.com/"}), @warm_context_iri, [{"namespace", "warm"}])
+      {:ok, snapshot} = Native.export_caches()
+      snapshot = Jason.decode!(snapshot)
+
+      assert {:error, {:incompatible_snapshot, _}} = Native.import_caches(Jason.encode!(%{snapshot | "format" => 999}))
+      assert {:error, {:incompatible_snapshot, _}} = Native.import_caches("not a snapshot")
+
+      poisoned = put_in(snapshot, ["contexts"], [%{"namespace" => "warm", "key" => "http://example.com/bad", "context" => "{"}])
+      assert {:error, {:incompatible_snapshot, reason}} = Native.import_caches(Jason.encode!(poisoned))
+      assert reason =~ "http://example.com/bad"
+
+      doc = Jason.encode!(%{"@context" => "http://example.com/bad", "name" => "x"})
+      assert {:error, {:loading_remote_context_failed, _}} = Native.expand(doc, [{"namespace", "warm"}])
+    end
+
+    test "snapshots hold only the cached contexts" do
+      {:ok, snapshot} = Native.export_caches()
+
+      assert snapshot |> Jason.decode!() |> Map.keys() |> Enum.sort() == ["contexts", "format", "version"]
+    end
+  end
+
+  describe "merge_documents semantic" do
+    @same_key_docs [
+      Jason.encode!(%{"@context" => %{"name" => "http://schema.org/name"}, "@id" => "http://example.com/alice", "name" => "Alice"}),
+      Jason.encode!(%{"@context" => %{"name" => "http://xmlns.com/foaf/0.1/name"}, "@id" => "http://example.com/alice", "name" => "Ali"})
+    ]
+
+    test "the same key under different contexts stays two properties" do
+      {:ok, json} = Native.merge_documents(@same_key_docs, [{"semantic", "true"}])
+
+      assert [alice] = Jason.decode!(json)
+      assert alice["@id"] == "http://example.com/alice"
+      assert alice["http://schema.org/name"] == [%{"@value" => "Alice"}]
+      assert alice["http://xmlns.com/foaf/0.1/name"] == [%{"@value" => "Ali"}]
+    end
+
+    test "compacts against a supplied context" do
+      context = Jason.encode!(%{"schema" => "http://schema.org/", "foaf" => "http://xmlns.com/foaf/0.1/"})
+      {:ok, json} = Native.merge_documents(@same_key_docs, [{"semantic", "true"}, {"context", context}])
+      merged = Jason.decode!(json)
+
+      assert merged["schema:name"] == "Alice"
+      assert merged["foaf:name"] == "Ali"
+    end
+
+    test "blank nodes of different documents never merge" do
+      docs =
+        for street <- ["1 Main St", "2 High St"] do
+          address = %{"@id" => "_:address", "http://schema.org/streetAddress" => street}
+          Jason.encode!(%{"@id" => "http://example.com/alice", "http://schema.org/address" => address})
+        end
+
+      {:ok, json, metadata} = Native.merge_documents(docs, [{"semantic", "true"}, {"relabel_bnodes", "true"}])
+      nodes = Jason.decode!(json)
+
+      assert [alice] = Enum.filter(nodes, &(&1["@id"] == "http://example.com/alice"))
+      assert length(alice["http://schema.org/address"]) == 2
+      assert [%{"_:address" => "_:d0b0"}, %{"_:address" => "_:d1b0"}] = Jason.decode!(metadata)["bnode_map"]
+    end
+  end
+
+  describe "malformed node references" do
+    @ctx %{"@vocab" => "http://example.org/"}
+
+    defp expand_json(doc, opts), do: Native.expand(Jason.encode!(doc), opts)
+
+    test "a non-string @id fails in strict mode with its path" do
+      doc = %{"@context" => @ctx, "@graph" => [%{"@id" => 42, "name" => "Alice"}]}
+
+      assert {:error, {:invalid_id_value, message}} = expand_json(doc, [{"strict", "true"}])
+      assert message =~ "/@graph/0/@id"
+    end
+
+    test "a malformed @id is dropped with a warning in lenient mode" do
+      doc = %{"@context" => @ctx, "@id" => %{"nested" => true}, "name" => "Alice"}
+
+      assert {:ok, json, metadata} = expand_json(doc, [{"collect_warnings", "true"}])
+      assert [%{"code" => "invalid_id_value", "path" => "/@id"}] = Jason.decode!(metadata)["warnings"]
+      [node] = Jason.decode!(json)
+      refute Map.has_key?(node, "@id")
+    end
+
+    test "non-string @type entries are rejected" do
+      doc = %{"@context" => @ctx, "@id" => "http://example.org/a", "@type" => ["Person", 7]}
+
+      assert {:error, {:invalid_type_value, message}} = expand_json(doc, [{"strict", "true"}])
+      assert message =~ "/@type/1"
+
+      assert {:ok, json, metadata} = expand_json(doc, [{"collect_warnings", "true"}])
+      assert [%{"code" => "invalid_type_value", "path" => "/@type/1"}] = Jason.decode!(metadata)["warnings"]
+      assert [%{"@type" => ["http://example.org/Person"]}] = Jason.decode!(json)
+    end
+
+    test "an array @type on a value object is an invalid typed value" do
+      doc = %{
+        "@context" => @ctx,
+        "@id" => "http://example.org/a",
+        "http://example.org/born" => %{"@value" => "1990-01-01", "@type" => ["http://www.w3.org/2001/XMLSchema#date"]}
+      }
+
+      assert {:error, {:invalid_typed_value, message}} = expand_json(doc, [{"strict", "true"}])
+      assert message =~ "/http:~1~1example.org~1born/@type"
+
+      assert {:ok, json, metadata} = expand_json(doc, [{"collect_warnings", "true"}])
+      assert [%{"code" => "invalid_typed_value"}] = Jason.decode!(metadata)["warnings"]
+      [node] = Jason.decode!(json)
+      assert node["http://example.org/born"] == %{"@value" => "1990-01-01"}
+    end
+
+    test "@reverse must be an object of node objects" do
+      doc = %{"@context" => @ctx, "@id" => "http://example.org/a", "@reverse" => "http://example.org/b"}
+      assert {:error, {:invalid_reverse_value, _}} = expand_json(doc, [{"strict", "true"}])
+
+      listed = %{
+        "@context" => @ctx,
+        "@id" => "http://example.org/a",
+        "@reverse" => %{"knows" => %{"@list" => [%{"@id" => "http://example.org/b"}]}}
+      }
+
+      assert {:error, {:invalid_reverse_property_value, message}} = expand_json(listed, [{"strict", "true"}])
+      assert message =~ "/@reverse/knows"
+
+      literal = %{"@context" => @ctx, "@id" => "http://example.org/a", "@reverse" => %{"knows" => "Bob"}}
+      assert {:ok, _json, metadata} = expand_json(literal, [{"collect_warnings", "true"}])
+      assert [%{"code" => "invalid_reverse_property_value", "path" => "/@reverse/knows"}] = Jason.decode!(metadata)["warnings"]
+    end
+  end
+
+  describe "processing_stats" do
+    @profiled Jason.encode!(%{"@context" => %{"@vocab" => "http://schema.org/"}, "name" => "Jane"})
+
+    defp timing(kind) do
+      {:ok, stats} = Native.processing_stats()
+      Jason.decode!(stats)["timings"][kind]
+    end
+
+    test "profiled calls accumulate time per operation" do
+      before = timing("expand")
+      assert {:ok, _} = Native.expand(@profiled, [{"profile", "true"}])
+      assert {:ok, _} = Native.expand(@profiled, [{"profile", "true"}])
+      after_expand = timing("expand")
+
+      assert after_expand["calls"] - before["calls"] >= 2
+      assert after_expand["nanos"] > before["nanos"]
+
+      diff_before = timing("diff_structural")
+      assert {:ok, _} = Native.diff_structural(~s({"a":1}), ~s({"a":2}), [{"profile", "true"}])
+      assert timing("diff_structural")["calls"] > diff_before["calls"]
+    end
+
+    test "calls without the profile option are not timed" do
+      before = timing("compact")
+      assert {:ok, _} = Native.compact(@profiled, ~s({"@vocab":"http://schema.org/"}), [])
+      assert timing("compact") == before
+    end
+
+    test "operation counts are reported alongside timings" do
+      {:ok, stats} = Native.processing_stats()
+      assert %{"diffs" => %{"structural" => _, "bytes_processed" => _}, "timings" => %{"patch" => _}} = Jason.decode!(stats)
+    end
+  end
+
+  describe "compact keyword aliases and omit_graph" do
+    @alias_context %{
+      "@vocab" => "http://schema.org/",
+      "graph" => "@graph",
+      "id" => "@id",
+      "type" => "@type",
+      "list" => "@list"
+    }
+
+    @one_node %{
+      "@context" => %{"@vocab" => "http://schema.org/"},
+      "@id" => "http://example.org/a",
+      "@type" => "Person",
+      "tags" => %{"@list" => ["a", "b"]}
+    }
+
+    @two_nodes %{
+      "@context" => %{"@vocab" => "http://schema.org/"},
+      "@graph" => [
+        %{"@id" => "http://example.org/a", "@type" => "Person"},
+        %{"@id" => "http://example.org/b", "name" => "B"}
+      ]
+    }
+
+    defp compact_aliased(doc, opts) do
+      {:ok, json} = Native.compact(Jason.encode!(doc), Jason.encode!(@alias_context), [{"unmapped", "vocab_relative"} | opts])
+      Jason.decode!(json)
+    end
+
+    defp expand_doc(doc) do
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+      Jason.decode!(json)
+    end
+
+    test "a single node drops the @graph wrapper and uses every alias" do
+      compacted = compact_aliased(@one_node, [])
+
+      assert %{"id" => "http://example.org/a", "type" => "Person", "tags" => %{"list" => ["a", "b"]}} = compacted
+      refute Map.has_key?(compacted, "graph")
+      assert expand_doc(compacted) == expand_doc(@one_node)
+    end
+
+    test "several nodes sit under the aliased @graph" do
+      compacted = compact_aliased(@two_nodes, [])
+
+      assert %{"graph" => [%{"id" => "http://example.org/a", "type" => "Person"}, %{"id" => "http://example.org/b"}]} =
+               compacted
+
+      assert expand_doc(compacted) == expand_doc(@two_nodes)
+    end
+
+    test "omit_graph false keeps the wrapper for a single node" do
+      compacted = compact_aliased(@one_node, [{"omit_graph", "false"}])
+
+      assert %{"graph" => [%{"id" => "http://example.org/a"}]} = compacted
+      refute Map.has_key?(compacted, "id")
+      assert expand_doc(compacted) == expand_doc(@one_node)
+    end
+
+    test "the omit_graph default follows the processing mode" do
+      assert %{"graph" => [_]} = compact_aliased(@one_node, [{"processing_mode", "json-ld-1.0"}])
+      assert %{"id" => _} = compact_aliased(@one_node, [{"processing_mode", "json-ld-1.1"}])
+
+      opts = [{"processing_mode", "json-ld-1.0"}, {"omit_graph", "true"}]
+      assert %{"id" => _} = compact_aliased(@one_node, opts)
+    end
+  end
+
+  describe "normalize_values" do
+    @mixed_a ~s([{"@id":"http://example.org/a","http://example.org/label":[{"@value":"Hi","@language":"EN-US","@direction":"LTR"}]}])
+    @mixed_b ~s([{"@id":"http://example.org/a","http://example.org/label":[{"@direction":"ltr","@language":"en-us","@value":"Hi"}]}])
+
+    test "value objects differing only in tag casing become identical" do
+      opts = [{"normalize_values", "true"}]
+      assert {:ok, a} = Native.expand(@mixed_a, opts)
+      assert {:ok, b} = Native.expand(@mixed_b, opts)
+
+      assert a == b
+      assert [%{"http://example.org/label" => [%{"@language" => "en-us", "@direction" => "ltr"}]}] = Jason.decode!(a)
+    end
+
+    test "null directions are dropped and @json literals are left alone" do
+      doc = ~s([{"http://example.org/p":[{"@value":"x","@language":"De","@direction":null},{"@value":{"Key":"V"},"@type":"@json"}]}])
+      assert {:ok, json} = Native.expand(doc, [{"normalize_values", "true"}])
+
+      assert [%{"http://example.org/p" => [%{"@value" => "x", "@language" => "de"} = plain, %{"@value" => %{"Key" => "V"}, "@type" => "@json"}]}] =
+               Jason.decode!(json)
+
+      refute Map.has_key?(plain, "@direction")
+    end
+  end
+
+  describe "diff_array_chunked" do
+    @events_old Enum.map(0..199, &%{"@id" => "urn:event:#{&1}", "seq" => &1, "kind" => "tick"})
+    @events_new @events_old
+                |> List.replace_at(5, %{"@id" => "urn:event:5", "seq" => 5, "kind" => "tock"})
+                |> List.delete_at(40)
+                |> List.insert_at(120, %{"@id" => "urn:event:new"})
+                |> Kernel.++([%{"@id" => "urn:event:tail"}])
+
+    defp drain(handle, max_items, acc \\ []) do
+      case Native.diff_array_chunked_next(handle, max_items) do
+        {:ok, fragment} -> drain(handle, max_items, [Jason.decode!(fragment) | acc])
+        {:done, fragment} -> Enum.reverse([Jason.decode!(fragment) | acc])
+      end
+    end
+
+    defp chunked_equals_one_shot(old, new, opts, max_items) do
+      {:ok, one_shot} = Native.diff_structural(Jason.encode!(old), Jason.encode!(new), opts)
+      {:ok, handle} = Native.diff_array_chunked_init(Jason.encode!(old), Jason.encode!(new), opts)
+      fragments = drain(handle, max_items)
+      keys = Enum.flat_map(fragments, &Map.keys/1)
+
+      assert length(keys) == length(Enum.uniq(keys))
+      assert Enum.reduce(fragments, %{}, &Map.merge(&2, &1)) == Jason.decode!(one_shot)
+      fragments
+    end
+
+    test "concatenated fragments equal the one-shot delta" do
+      for max_items <- [1, 7, 64, 10_000] do
+        fragments = chunked_equals_one_shot(@events_old, @events_new, [], max_items)
+        if max_items < 100, do: assert(length(fragments) > 1)
+      end
+    end
+
+    test "the equivalence holds on reordered input with repeated items" do
+      old = Enum.map(0..59, &%{"kind" => "k#{rem(&1, 7)}"})
+      new = Enum.drop(old, 15) ++ Enum.reverse(Enum.take(old, 15))
+
+      for max_items <- [1, 13, 10_000] do
+        fragments = chunked_equals_one_shot(old, new, [], max_items)
+        assert fragments |> Enum.flat_map(&Map.values/1) |> Enum.any?(&match?(["", _, 3], &1))
+      end
+    end
+
+    test "the equivalence holds without move detection" do
+      chunked_equals_one_shot(@events_old, @events_new, [{"include_moves", "false"}], 25)
+    end
+
+    test "the stream can stop early" do
+      fragments = JsonldEx.Diff.Performance.stream_array_diff(@events_old, @events_new, max_items: 10)
+      assert [%{} | _] = Enum.take(fragments, 1)
+    end
+
+    test "inputs must be arrays" do
+      assert {:error, _} = Native.diff_array_chunked_init(~s({"a":1}), ~s([1]), [])
+    end
+  end
+
+  describe "graph_stats" do
+    @staff Jason.encode!(%{
+             "@context" => %{"@vocab" => "http://schema.org/"},
+             "@graph" => [
+               %{
+                 "@id" => "http://example.org/alice",
+                 "@type" => ["Person", "Employee"],
+                 "name" => "Alice",
+                 "knows" => %{"@id" => "http://example.org/bob"},
+                 "address" => %{"@type" => "PostalAddress", "streetAddress" => "Main St"}
+               },
+               %{"@id" => "http://example.org/bob", "@type" => "Person", "name" => ["Bob", "Robert"]},
+               %{"@id" => "http://example.org/alice", "jobTitle" => "Engineer"}
+             ]
+           })
+
+    test "counts nodes, types and blank nodes of the flattened graph" do
+      assert {:ok, json} = Native.graph_stats(@staff)
+      stats = Jason.decode!(json)
+
+      assert stats["nodes"] == 3
+      assert stats["types"] == 3
+      assert stats["blank_nodes"] == 1
+      assert stats["named_graphs"] == 0
+      assert stats["max_depth"] == 2
+    end
+
+    test "reports how often each property is used" do
+      {:ok, json} = Native.graph_stats(@staff)
+
+      assert %{"http://schema.org/name" => 3, "http://schema.org/jobTitle" => 1, "http://schema.org/knows" => 1} =
+               Jason.decode!(json)["properties"]
+    end
+
+    test "invalid JSON is an error" do
+      assert {:error, _} = Native.graph_stats("{")
+    end
+  end
+
+  describe "@none in container maps" do
+    @map_context %{
+      "@vocab" => "http://example.org/",
+      "none" => "@none",
+      "label" => %{"@container" => "@language"},
+      "byIndex" => %{"@container" => "@index"},
+      "byId" => %{"@container" => "@id"},
+      "byType" => %{"@container" => "@type"}
+    }
+
+    @map_doc %{
+      "@context" => @map_context,
+      "@id" => "http://example.org/s",
+      "@type" => "Thing",
+      "label" => %{"en" => "Hi", "none" => "plain"},
+      "byIndex" => %{"a" => %{"name" => "A"}, "@none" => %{"name" => "N"}},
+      "byId" => %{"http://example.org/x" => %{"name" => "X"}, "none" => %{"name" => "anon"}},
+      "byType" => %{"Person" => %{"@id" => "http://example.org/p"}, "@none" => "http://example.org/q"}
+    }
+
+    defp expand_map_doc(doc) do
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+      [node] = Jason.decode!(json)
+      node
+    end
+
+    defp sorted(values), do: Enum.sort_by(values, &Jason.encode!/1)
+
+    test "@none entries expand without a language, index, @id or type" do
+      node = expand_map_doc(@map_doc)
+
+      assert sorted(node["http://example.org/label"]) ==
+               sorted([%{"@value" => "Hi", "@language" => "en"}, %{"@value" => "plain"}])
+
+      assert sorted(node["http://example.org/byIndex"]) ==
+               sorted([
+                 %{"@index" => "a", "http://example.org/name" => %{"@value" => "A"}},
+                 %{"http://example.org/name" => %{"@value" => "N"}}
+               ])
+
+      assert sorted(node["http://example.org/byId"]) ==
+               sorted([
+                 %{"@id" => "http://example.org/x", "http://example.org/name" => %{"@value" => "X"}},
+                 %{"http://example.org/name" => %{"@value" => "anon"}}
+               ])
+
+      assert sorted(node["http://example.org/byType"]) ==
+               sorted([
+                 %{"@id" => "http://example.org/p", "@type" => "http://example.org/Person"},
+                 %{"@id" => "http://example.org/q"}
+               ])
+    end
+
+    test "compaction keys unlabelled items under the @none alias and round-trips" do
+      {:ok, expanded} = Native.expand(Jason.encode!(@map_doc), [])
+      {:ok, json} = Native.compact(expanded, Jason.encode!(@map_context), [{"unmapped", "vocab_relative"}])
+      compacted = Jason.decode!(json)
+
+      assert compacted["label"] == %{"en" => "Hi", "none" => "plain"}
+      assert compacted["byIndex"] == %{"a" => %{"name" => "A"}, "none" => %{"name" => "N"}}
+      assert compacted["byId"] == %{"http://example.org/x" => %{"name" => "X"}, "none" => %{"name" => "anon"}}
+      assert compacted["byType"] == %{"Person" => "http://example.org/p", "none" => "http://example.org/q"}
+
+      roundtrip = expand_map_doc(compacted)
+      original = expand_map_doc(@map_doc)
+
+      for property <- ["label", "byIndex", "byId", "byType"] do
+        iri = "http://example.org/" <> property
+        assert sorted(roundtrip[iri]) == sorted(original[iri])
+      end
+    end
+
+    test "framing output uses the same keyed maps" do
+      frame = %{"@context" => @map_context, "@type" => "Thing"}
+      {:ok, json} = Native.frame(Jason.encode!(@map_doc), Jason.encode!(frame), [])
+      framed = Jason.decode!(json)
+
+      assert framed["byId"]["http://example.org/x"] == %{"name" => "X"}
+      assert framed["byIndex"]["a"]["name"] == "A"
+      assert framed["byIndex"]["none"]["name"] == "N"
+    end
+
+    test "@type maps are rebuilt keyed by each node's first type" do
+      doc = %{
+        "@context" => @map_context,
+        "@id" => "http://example.org/s",
+        "byType" => %{
+          "Person" => "http://example.org/p",
+          "Club" => %{"@id" => "http://example.org/chess", "@type" => "Hobby", "name" => "Chess"},
+          "none" => "http://example.org/q"
+        }
+      }
+
+      {:ok, expanded} = Native.expand(Jason.encode!(doc), [])
+      {:ok, json} = Native.compact(expanded, Jason.encode!(@map_context), [{"unmapped", "vocab_relative"}])
+      compacted = Jason.decode!(json)
+
+      assert compacted["byType"] == %{
+               "Person" => "http://example.org/p",
+               "Club" => %{"@id" => "http://example.org/chess", "@type" => "Hobby", "name" => "Chess"},
+               "none" => "http://example.org/q"
+             }
+
+      assert sorted(expand_map_doc(compacted)["http://example.org/byType"]) ==
+               sorted(expand_map_doc(doc)["http://example.org/byType"])
+    end
+  end
+
+  describe "operational move" do
+    test "a relocated array element is diffed and patched as a single move" do
+      old = %{"@id" => "http://example.org/list", "items" => ["a", "b", "c", "d"]}
+      new = %{"@id" => "http://example.org/list", "items" => ["b", "c", "d", "a"]}
+
+      {:ok, patch} = Native.diff_operational(Jason.encode!(old), Jason.encode!(new), [])
+
+      assert [%{"type" => "move", "from" => ["items", "0"], "path" => ["items", "3"]}] =
+               Jason.decode!(patch)["operations"]
+
+      {:ok, patched} = Native.patch_operational(Jason.encode!(old), patch, [])
+      assert Jason.decode!(patched) == new
+    end
+
+    test "moves keep the moved value and land after it has been removed" do
+      old = %{"items" => [%{"n" => 1}, %{"n" => 2}, %{"n" => 3}]}
+
+      patch = %{
+        "operations" => [
+          %{"type" => "move", "from" => ["items", "2"], "path" => ["items", "0"], "timestamp" => 1}
+        ]
+      }
+
+      {:ok, patched} = Native.patch_operational(Jason.encode!(old), Jason.encode!(patch), [])
+      assert Jason.decode!(patched) == %{"items" => [%{"n" => 3}, %{"n" => 1}, %{"n" => 2}]}
+    end
+
+    test "arrays whose contents change still diff as deletes and inserts" do
+      old = %{"items" => ["a", "b"]}
+      new = %{"items" => ["b", "c"]}
+
+      {:ok, patch} = Native.diff_operational(Jason.encode!(old), Jason.encode!(new), [])
+      types = Jason.decode!(patch)["operations"] |> Enum.map(& &1["type"]) |> Enum.uniq() |> Enum.sort()
+      assert types == ["delete", "insert"]
+
+      {:ok, patched} = Native.patch_operational(Jason.encode!(old), patch, [])
+      assert Jason.decode!(patched) == new
+    end
+  end
+
+  describe "operational rename" do
+    test "a key renamed with its value unchanged is a single rename" do
+      old = %{"@id" => "http://example.org/a", "givenName" => %{"@value" => "Alice", "@language" => "en"}}
+      new = %{"@id" => "http://example.org/a", "firstName" => %{"@value" => "Alice", "@language" => "en"}}
+
+      {:ok, patch} = Native.diff_operational(Jason.encode!(old), Jason.encode!(new), [])
+
+      assert [%{"type" => "rename", "from" => ["givenName"], "path" => ["firstName"]}] =
+               Jason.decode!(patch)["operations"]
+
+      {:ok, patched} = Native.patch_operational(Jason.encode!(old), patch, [])
+      assert Jason.decode!(patched) == new
+    end
+
+    test "a renamed key whose value also changed stays a delete and a set" do
+      old = %{"givenName" => "Alice"}
+      new = %{"firstName" => "Alicia"}
+
+      {:ok, patch} = Native.diff_operational(Jason.encode!(old), Jason.encode!(new), [])
+      types = Jason.decode!(patch)["operations"] |> Enum.map(& &1["type"]) |> Enum.sort()
+      assert types == ["delete", "set"]
+    end
+  end
+
+  describe "find_similar_nodes" do
+    @people %{
+      "@context" => %{"@vocab" => "http://schema.org/"},
+      "@graph" => [
+        %{"@id" => "http://example.org/p1", "@type" => "Person", "name" => "Alice Smith", "email" => "alice@example.org", "dateModified" => "2024-01-01"},
+        %{"@id" => "http://example.org/p2", "@type" => "Person", "name" => "alice  SMITH", "email" => "alice@example.org", "dateModified" => "2025-02-02"},
+        %{"@id" => "http://example.org/o1", "@type" => "Organization", "name" => "Alice Smith", "email" => "alice@example.org"},
+        %{"@id" => "http://example.org/x1", "@type" => "Person", "name" => "Bob Jones", "email" => "bob@example.org"},
+        %{"@id" => "http://example.org/x2", "@type" => "Person", "name" => "Bob Jones", "email" => "bob@example.org"},
+        %{"@id" => "http://example.org/c", "@type" => "Person", "name" => "Carol White", "telephone" => "555-0100"}
+      ]
+    }
+
+    @ignore_modified {"ignore", ~s(["http://schema.org/dateModified"])}
+
+    defp similar_nodes(opts) do
+      {:ok, json} = Native.find_similar_nodes(Jason.encode!(@people), opts)
+      Jason.decode!(json)
+    end
+
+    test "exact duplicates are reported apart from fuzzy matches" do
+      result = similar_nodes([@ignore_modified])
+
+      assert result["exact"] == [%{"nodes" => ["http://example.org/x1", "http://example.org/x2"]}]
+      refute Enum.any?(result["similar"], &(&1["nodes"] == ["http://example.org/x1", "http://example.org/x2"]))
+    end
+
+    test "casing and spacing differences still match, naming the differing property" do
+      result = similar_nodes([@ignore_modified])
+
+      assert %{"similarity" => similarity, "differing_properties" => ["http://schema.org/name"]} =
+               Enum.find(result["similar"], &(&1["nodes"] == ["http://example.org/p1", "http://example.org/p2"]))
+
+      assert similarity >= 0.8
+      refute Enum.any?(result["similar"], &("http://example.org/c" in &1["nodes"]))
+    end
+
+    test "ignored properties are left out of the similarity" do
+      with_ignore = similar_nodes([@ignore_modified, {"threshold", "0"}])
+      without_ignore = similar_nodes([{"threshold", "0"}])
+      pair = ["http://example.org/p1", "http://example.org/p2"]
+
+      assert Enum.find(with_ignore["similar"], &(&1["nodes"] == pair))["similarity"] >
+               Enum.find(without_ignore["similar"], &(&1["nodes"] == pair))["similarity"]
+    end
+
+    test "same_type only pairs nodes sharing a @type" do
+      untyped = similar_nodes([@ignore_modified])
+      typed = similar_nodes([@ignore_modified, {"same_type", "true"}])
+
+      assert Enum.any?(untyped["similar"], &("http://example.org/o1" in &1["nodes"]))
+      refute Enum.any?(typed["similar"], &("http://example.org/o1" in &1["nodes"]))
+    end
+
+    test "blank nodes shared deep in a DAG are compared without re-walking them per path" do
+      levels =
+        for i <- 0..39 do
+          next = %{"@id" => "_:n#{i + 1}"}
+          %{"@id" => "_:n#{i}", "left" => next, "right" => next}
+        end
+
+      graph = levels ++ [%{"@id" => "_:n40", "name" => "leaf"}, %{"@id" => "http://example.org/a", "root" => %{"@id" => "_:n0"}}, %{"@id" => "http://example.org/b", "root" => %{"@id" => "_:n0"}}]
+      doc = %{"@context" => %{"@vocab" => "http://example.org/"}, "@graph" => graph}
+
+      {:ok, json} = Native.find_similar_nodes(Jason.encode!(doc), [])
+      assert %{"nodes" => ["http://example.org/a", "http://example.org/b"]} in Jason.decode!(json)["exact"]
+    end
+  end
+
+  describe "@import" do
+    @import_iri "https://contexts.example.com/import-base.jsonld"
+
+    setup do
+      base = %{
+        "@context" => %{
+          "@vocab" => "http://schema.org/",
+          "name" => "http://schema.org/name",
+          "homepage" => %{"@id" => "http://schema.org/url", "@type" => "@id"}
+        }
+      }
+
+      {:ok, _} = Native.cache_context(Jason.encode!(base), @import_iri)
+      :ok
+    end
+
+    defp expand_with(context) do
+      doc = %{"@context" => context, "name" => "Alice", "homepage" => "https://alice.example/"}
+      Native.expand(Jason.encode!(doc), [])
+    end
+
+    test "an imported context applies under local overrides" do
+      {:ok, json} = expand_with(%{"@import" => @import_iri, "name" => "http://xmlns.com/foaf/0.1/name"})
+      [node] = Jason.decode!(json)
+
+      assert node["http://xmlns.com/foaf/0.1/name"] == %{"@value" => "Alice"}
+      refute Map.has_key?(node, "http://schema.org/name")
+      assert node["http://schema.org/url"] == %{"@id" => "https://alice.example/"}
+    end
+
+    test "the import resolves against @base" do
+      {:ok, json} = expand_with([%{"@base" => "https://contexts.example.com/v1/"}, %{"@import" => "../import-base.jsonld"}])
+      assert [%{"http://schema.org/name" => %{"@value" => "Alice"}}] = Jason.decode!(json)
+    end
+
+    test "an import that isn't cached is an error" do
+      assert {:error, {:loading_remote_context_failed, message}} =
+               expand_with(%{"@import" => "https://contexts.example.com/not-cached.jsonld"})
+
+      assert message =~ "not-cached.jsonld"
+      assert {:error, {:invalid_import_value, _}} = expand_with(%{"@import" => 5})
+    end
+  end
+
+  describe "convert_patch" do
+    @formats ["structural", "json_patch", "operational"]
+    @corpus "test/fixtures/patch_conversion/corpus.json" |> File.read!() |> Jason.decode!()
+
+    defp source_patch("structural", old, new), do: Native.diff_structural(old, new, [{"text_diff_threshold", "10"}])
+    defp source_patch("operational", old, new), do: Native.diff_operational(old, new, [])
+
+    defp source_patch("json_patch", old, new) do
+      {:ok, structural} = source_patch("structural", old, new)
+      Native.convert_patch(structural, "structural", "json_patch", [{"document", old}])
+    end
+
+    defp apply_patch("structural", doc, patch), do: Native.patch_structural(doc, patch, [])
+    defp apply_patch("operational", doc, patch), do: Native.patch_operational(doc, patch, [])
+
+    defp apply_patch("json_patch", doc, patch) do
+      result = patch |> Jason.decode!() |> Enum.reduce(Jason.decode!(doc), &apply_json_patch_op/2)
+      {:ok, Jason.encode!(result)}
+    end
+
+    # A plain RFC 6902 applier, so JSON Patch output isn't checked against the converter itself
+    defp apply_json_patch_op(%{"op" => "add", "path" => path, "value" => value}, doc), do: pointer_add(doc, pointer(path), value)
+    defp apply_json_patch_op(%{"op" => "remove", "path" => path}, doc), do: elem(pointer_remove(doc, pointer(path)), 1)
+
+    defp apply_json_patch_op(%{"op" => "replace", "path" => path, "value" => value}, doc) do
+      case pointer(path) do
+        [] -> value
+        segments -> doc |> pointer_remove(segments) |> elem(1) |> pointer_add(segments, value)
+      end
+    end
+
+    defp apply_json_patch_op(%{"op" => "move", "from" => from, "path" => path}, doc) do
+      {value, doc} = pointer_remove(doc, pointer(from))
+      pointer_add(doc, pointer(path), value)
+    end
+
+    defp apply_json_patch_op(%{"op" => "copy", "from" => from, "path" => path}, doc) do
+      pointer_add(doc, pointer(path), pointer_get(doc, pointer(from)))
+    end
+
+    defp apply_json_patch_op(%{"op" => "test", "path" => path, "value" => value}, doc) do
+      ^value = pointer_get(doc, pointer(path))
+      doc
+    end
+
+    defp pointer(""), do: []
+    defp pointer("/" <> path), do: path |> String.split("/") |> Enum.map(&(&1 |> String.replace("~1", "/") |> String.replace("~0", "~")))
+
+    defp pointer_get(doc, []), do: doc
+    defp pointer_get(list, [segment | rest]) when is_list(list), do: list |> Enum.fetch!(String.to_integer(segment)) |> pointer_get(rest)
+    defp pointer_get(map, [key | rest]), do: map |> Map.fetch!(key) |> pointer_get(rest)
+
+    defp pointer_add(_doc, [], value), do: value
+
+    defp pointer_add(list, [segment], value) when is_list(list) do
+      index = if segment == "-", do: length(list), else: String.to_integer(segment)
+      true = index <= length(list)
+      List.insert_at(list, index, value)
+    end
+
+    defp pointer_add(map, [key], value) when is_map(map), do: Map.put(map, key, value)
+    defp pointer_add(list, [segment | rest], value) when is_list(list), do: List.update_at(list, String.to_integer(segment), &pointer_add(&1, rest, value))
+    defp pointer_add(map, [key | rest], value) when is_map(map), do: Map.update!(map, key, &pointer_add(&1, rest, value))
+
+    defp pointer_remove(list, [segment]) when is_list(list) do
+      index = String.to_integer(segment)
+      true = index < length(list)
+      List.pop_at(list, index)
+    end
+
+    defp pointer_remove(map, [key]) when is_map(map), do: Map.pop!(map, key)
+
+    defp pointer_remove(list, [segment | rest]) when is_list(list) do
+      index = String.to_integer(segment)
+      {value, item} = pointer_remove(Enum.fetch!(list, index), rest)
+      {value, List.replace_at(list, index, item)}
+    end
+
+    defp pointer_remove(map, [key | rest]) when is_map(map) do
+      {value, item} = pointer_remove(Map.fetch!(map, key), rest)
+      {value, Map.put(map, key, item)}
+    end
+
+    test "every direction reproduces the new document across the corpus" do
+      for %{"name" => name, "old" => old, "new" => new} <- @corpus, from <- @formats, to <- @formats, from != to do
+        old_json = Jason.encode!(old)
+        {:ok, patch} = source_patch(from, old_json, Jason.encode!(new))
+        assert {:ok, converted} = Native.convert_patch(patch, from, to, [{"document", old_json}]), "#{name}: #{from} -> #{to}"
+        assert {:ok, patched} = apply_patch(to, old_json, converted)
+        assert Jason.decode!(patched) == new, "#{name}: #{from} -> #{to}"
+      end
+    end
+
+    test "a JSON Patch converts to the expected operations" do
+      doc = ~s({"a":[1,2],"x":1})
+
+      patch =
+        Jason.encode!([
+          %{"op" => "copy", "from" => "/x", "path" => "/y"},
+          %{"op" => "add", "path" => "/a/-", "value" => 3},
+          %{"op" => "test", "path" => "/x", "value" => 1}
+        ])
+
+      {:ok, json} = Native.convert_patch(patch, "json_patch", "operational", [{"document", doc}])
+
+      assert [%{"type" => "set", "path" => ["y"], "value" => 1}, %{"type" => "insert", "path" => ["a", "2"], "value" => 3}] =
+               Jason.decode!(json)["operations"]
+    end
+
+    test "a failing test operation is a conflict" do
+      patch = ~s([{"op":"test","path":"/x","value":2}])
+      assert {:error, {:patch_conflict, ["/x"]}} = Native.convert_patch(patch, "json_patch", "structural", [{"document", ~s({"x":1})}])
+    end
+
+    test "a JSON Patch whose target is missing from the document is an error" do
+      doc = [{"document", ~s({"a":[1],"x":1})}]
+
+      for {patch, target} <- [
+            {~s([{"op":"remove","path":"/y"}]), "/y"},
+            {~s([{"op":"replace","path":"/a/3","value":1}]), "/a/3"},
+            {~s([{"op":"move","from":"/q","path":"/z"}]), "/q"},
+            {~s([{"op":"add","path":"/q/r","value":1}]), "/q/r"}
+          ] do
+        assert {:error, message} = Native.convert_patch(patch, "json_patch", "operational", doc)
+        assert message =~ target
+      end
+    end
+
+    test "entries that need the document are untranslatable without it" do
+      {:ok, text_diff} = source_patch("structural", ~s({"t":"the quick brown fox"}), ~s({"t":"the quick red fox"}))
+      assert {:error, {:untranslatable_operation, json}} = Native.convert_patch(text_diff, "structural", "json_patch", [])
+      assert [%{"path" => "/t", "entry" => [%{"text_diff" => _}, 0, 2]}] = Jason.decode!(json)
+
+      copy = ~s([{"op":"copy","from":"/x","path":"/y"},{"op":"remove","path":"/x"}])
+      assert {:error, {:untranslatable_operation, json}} = Native.convert_patch(copy, "json_patch", "operational", [])
+      assert [%{"path" => "/y", "entry" => %{"op" => "copy"}}] = Jason.decode!(json)
+
+      assert {:error, _} = Native.convert_patch(copy, "json_patch", "structural", [])
+    end
+
+    test "plain edits convert without the document" do
+      {:ok, json} = Native.convert_patch(~s([{"op":"replace","path":"/a~1b","value":2}]), "json_patch", "operational", [])
+      assert [%{"type" => "set", "path" => ["a/b"], "value" => 2}] = Jason.decode!(json)["operations"]
+    end
+
+    test "unknown formats are rejected" do
+      assert {:error, message} = Native.convert_patch("[]", "json_patch", "yaml", [])
+      assert message =~ "yaml"
+    end
+  end
+
+  describe "@type: @id coercion in RDF" do
+    @coerced_context %{"ex" => "http://example.com/", "knows" => %{"@id" => "ex:knows", "@type" => "@id"}}
+
+    test "to_rdf and canonicalization emit coerced prefixed names as IRIs" do
+      doc = Jason.encode!(%{"@context" => @coerced_context, "@id" => "ex:alice", "knows" => ["ex:bob", "_:carol"]})
+
+      {:ok, nquads} = Native.to_rdf(doc, [])
+      assert nquads =~ "<http://example.com/alice> <http://example.com/knows> <http://example.com/bob> .\n"
+      assert nquads =~ ~r{<http://example.com/knows> _:b\d+ \.}
+
+      {:ok, canonical} = Native.normalize_rdf_graph(doc, "urdna2015")
+      assert canonical =~ "<http://example.com/knows> <http://example.com/bob> ."
+      refute canonical =~ "\"ex:bob\""
+    end
+
+    test "the semantic diff treats coerced values as node references" do
+      old = Jason.encode!(%{"@context" => @coerced_context, "@id" => "ex:alice", "knows" => "ex:bob"})
+      new = Jason.encode!(%{"@context" => @coerced_context, "@id" => "ex:alice", "knows" => "ex:dave"})
+
+      {:ok, diff} = Native.diff_semantic(old, new, [])
+      assert [%{"object" => "http://example.com/dave"}] = Jason.decode!(diff)["added_triples"]
+      assert [%{"object" => "http://example.com/bob"}] = Jason.decode!(diff)["removed_triples"]
+    end
+
+    test "the semantic diff resolves relative coerced values against @base" do
+      context = Map.merge(@coerced_context, %{"@base" => "http://example.com/people/", "@vocab" => "http://vocab.example/"})
+      old = Jason.encode!(%{"@context" => context, "@id" => "ex:alice", "knows" => "bob"})
+      new = Jason.encode!(%{"@context" => context, "@id" => "ex:alice", "knows" => "../dave"})
+
+      {:ok, diff} = Native.diff_semantic(old, new, [])
+      assert [%{"object" => "http://example.com/dave"}] = Jason.decode!(diff)["added_triples"]
+      assert [%{"object" => "http://example.com/people/bob"}] = Jason.decode!(diff)["removed_triples"]
+    end
+
+    test "the semantic diff rejects a local context that doesn't process" do
+      doc = Jason.encode!(%{"@context" => %{"knows" => %{"@id" => 7}}, "@id" => "http://example.com/alice", "knows" => "x"})
+      assert {:error, {:invalid_iri_mapping, _}} = Native.diff_semantic(doc, doc, [])
+    end
+  end
+
+  describe "infer_datatypes" do
+    @xsd "http://www.w3.org/2001/XMLSchema#"
+    @sloppy Jason.encode!(%{
+              "@context" => %{"@vocab" => "http://schema.org/", "ash" => "https://ash-hq.org/ontology/"},
+              "@id" => "http://example.com/alice",
+              "birthDate" => "1990-05-01",
+              "deathDate" => "someday",
+              "numberOfPages" => 300,
+              "ash:allow_nil" => "true",
+              "name" => "Alice"
+            })
+
+    test "well-known properties take their datatype from the table" do
+      {:ok, json} = Native.expand(@sloppy, [{"infer_datatypes", "true"}])
+      [node] = Jason.decode!(json)
+
+      assert node["http://schema.org/birthDate"] == %{"@value" => "1990-05-01", "@type" => @xsd <> "date"}
+      assert node["https://ash-hq.org/ontology/allow_nil"] == %{"@value" => "true", "@type" => @xsd <> "boolean"}
+      assert node["http://schema.org/name"] == %{"@value" => "Alice"}
+
+      {:ok, plain} = Native.expand(@sloppy, [])
+      assert hd(Jason.decode!(plain))["http://schema.org/birthDate"] == %{"@value" => "1990-05-01"}
+    end
+
+    test "values outside the lexical space are reported" do
+      {:ok, _json, metadata} = Native.expand(@sloppy, [{"infer_datatypes", "true"}, {"collect_warnings", "true"}])
+      assert [%{"code" => "invalid_inferred_literal", "path" => "/deathDate"}] = Jason.decode!(metadata)["warnings"]
+    end
+
+    test "explicit coercion wins over the table" do
+      doc = Jason.encode!(%{"@context" => %{"birthDate" => %{"@id" => "http://schema.org/birthDate", "@type" => "xsd:string"}}, "birthDate" => "1990"})
+      {:ok, json} = Native.expand(doc, [{"infer_datatypes", "true"}])
+      assert [%{"http://schema.org/birthDate" => %{"@type" => @xsd <> "string"}}] = Jason.decode!(json)
+    end
+
+    test "the table can be extended and trimmed" do
+      table = Jason.encode!(%{"http://schema.org/name" => "xsd:token", "http://schema.org/birthDate" => nil})
+      {:ok, nquads} = Native.to_rdf(@sloppy, [{"infer_datatypes", "true"}, {"datatype_table", table}])
+
+      assert nquads =~ ~s(<http://schema.org/name> "Alice"^^<#{@xsd}token> .)
+      assert nquads =~ ~s(<http://schema.org/birthDate> "1990-05-01" .)
+      assert nquads =~ ~s(<http://schema.org/numberOfPages> "300"^^<#{@xsd}integer> .)
+
+      assert {:error, {:invalid_datatype_table, _}} = Native.expand(@sloppy, [{"infer_datatypes", "true"}, {"datatype_table", "[]"}])
+    end
+  end
+
+  describe "flatten node_map" do
+    test "nodes are keyed by @id, blank nodes by their assigned label" do
+      doc = %{
+        "@context" => %{"@vocab" => "http://example.com/"},
+        "@id" => "http://example.com/1",
+        "knows" => %{"name" => "anonymous", "knows" => %{"@id" => "http://example.com/2", "name" => "Two"}}
+      }
+
+      {:ok, json} = Native.flatten(Jason.encode!(doc), nil, [{"node_map", "true"}])
+      nodes = Jason.decode!(json)
+
+      assert Enum.sort(Map.keys(nodes)) == ["_:b0", "http://example.com/1", "http://example.com/2"]
+      assert nodes["http://example.com/1"]["http://example.com/knows"] == [%{"@id" => "_:b0"}]
+      assert nodes["_:b0"]["@id"] == "_:b0"
+      assert nodes["http://example.com/2"]["http://example.com/name"] == [%{"@value" => "Two"}]
+    end
+
+    test "named graphs are keyed too and the context is kept" do
+      doc = %{"@id" => "http://example.com/g", "@graph" => [%{"@id" => "http://example.com/3", "http://example.com/p" => "x"}]}
+      context = ~s({"@vocab":"http://example.com/"})
+
+      {:ok, json} = Native.flatten(Jason.encode!(doc), context, [{"node_map", "true"}])
+      keyed = Jason.decode!(json)
+
+      assert keyed["@context"] == %{"@vocab" => "http://example.com/"}
+      assert %{"http://example.com/3" => %{"@id" => "http://example.com/3"}} = keyed["http://example.com/g"]["@graph"]
+    end
+  end
+
+  describe "@list diffing" do
+    defp list_doc(items), do: Jason.encode!(%{"@id" => "http://example.com/s", "http://example.com/p" => %{"@list" => items}})
+
+    @list_fixtures [
+      {"item inserted in the middle", ["a", "b", "c"], ["a", "x", "b", "c"], %{"_t" => "a", "1" => ["x"]}},
+      {"list reordered", ["a", "b", "c"], ["c", "a", "b"], %{"_t" => "a", "_2" => ["c", 0, 0], "0" => ["c"]}},
+      {"item edited in place", [%{"n" => 1}, %{"n" => 2}, "c"], [%{"n" => 1}, %{"n" => 20}, "c"], %{"_t" => "a", "_1" => %{"n" => [2, 20]}}}
+    ]
+
+    test "list deltas are order-sensitive LCS edits that patch in place" do
+      for {name, old_items, new_items, expected} <- @list_fixtures do
+        {old, new} = {list_doc(old_items), list_doc(new_items)}
+        {:ok, diff} = Native.diff_structural(old, new, [])
+        assert %{"http://example.com/p" => %{"@list" => ^expected}} = Jason.decode!(diff), name
+
+        {:ok, patched} = Native.patch_structural(old, diff, [])
+        assert Jason.decode!(patched) == Jason.decode!(new), name
+      end
+    end
+
+    test "the semantic diff reports a changed list as one modified property" do
+      {:ok, json} = Native.diff_semantic(list_doc(["a", "b", "c"]), list_doc(["a", "x", "c"]), [])
+      diff = Jason.decode!(json)
+
+      assert [%{"node_id" => "http://example.com/s", "modified_properties" => [modified]}] = diff["modified_nodes"]
+      assert %{"property" => "http://example.com/p", "change_type" => "list"} = modified
+      assert Enum.map(modified["old_value"]["list"], & &1["value"]) == ["a", "b", "c"]
+      assert Enum.map(modified["new_value"]["list"], & &1["value"]) == ["a", "x", "c"]
+      assert length(diff["added_triples"]) == 1
+    end
+
+    test "the semantic diff reports a reordered list as an order change" do
+      {:ok, json} = Native.diff_semantic(list_doc(["a", "b"]), list_doc(["b", "a"]), [])
+      diff = Jason.decode!(json)
+
+      assert [%{"node_id" => "http://example.com/s", "modified_properties" => [modified]} = node] = diff["modified_nodes"]
+      assert %{"property" => "http://example.com/p", "change_type" => "reorder", "order" => [1, 0]} = modified
+      assert Enum.map(modified["new_value"]["list"], & &1["value"]) == ["b", "a"]
+      assert node["added_properties"] == [] and node["removed_properties"] == []
+    end
+
+    test "the semantic diff keeps repeated list items in their relative order" do
+      {:ok, json} = Native.diff_semantic(list_doc(["a", "b", "a"]), list_doc(["a", "a", "b"]), [])
+
+      assert [%{"modified_properties" => [%{"change_type" => "reorder", "order" => [0, 2, 1]}]}] =
+               Jason.decode!(json)["modified_nodes"]
+    end
+  end
+
+  describe "compact unmapped" do
+    @unmapped_doc Jason.encode!(%{
+                    "@id" => "http://example.org/a",
+                    "http://schema.org/name" => "A",
+                    "http://other.org/p" => "x",
+                    "http://schema.org/knows" => %{"@id" => "http://example.org/b"}
+                  })
+
+    @unmapped_context Jason.encode!(%{
+                        "@vocab" => "http://schema.org/",
+                        "o" => "http://other.org/",
+                        "knows" => %{"@type" => "@id"}
+                      })
+
+    defp compact_unmapped(opts) do
+      {:ok, json} = Native.compact(@unmapped_doc, @unmapped_context, opts)
+      Map.delete(Jason.decode!(json), "@context")
+    end
+
+    test "keep_iri, the default, writes the full IRI of properties without a term" do
+      expected = %{
+        "@id" => "http://example.org/a",
+        "http://schema.org/name" => "A",
+        "http://other.org/p" => "x",
+        "knows" => "http://example.org/b"
+      }
+
+      assert compact_unmapped([]) == expected
+      assert compact_unmapped([{"unmapped", "keep_iri"}]) == expected
+    end
+
+    test "vocab_relative compacts through @vocab and prefixes" do
+      assert compact_unmapped([{"unmapped", "vocab_relative"}]) ==
+               %{"@id" => "http://example.org/a", "name" => "A", "o:p" => "x", "knows" => "http://example.org/b"}
+    end
+
+    test "drop leaves properties without a term out" do
+      assert compact_unmapped([{"unmapped", "drop"}]) == %{"@id" => "http://example.org/a", "knows" => "http://example.org/b"}
+
+      assert {:error, {:lossy_compaction, paths}} =
+               Native.compact(@unmapped_doc, @unmapped_context, [{"unmapped", "drop"}, {"safe", "true"}])
+
+      assert paths != []
+    end
+  end
+
+  describe "context-level @protected" do
+    @protected_context %{
+      "@protected" => true,
+      "name" => "http://schema.org/name",
+      "knows" => %{"@id" => "http://schema.org/knows", "@type" => "@id"}
+    }
+
+    defp expand_with_contexts(contexts, body) do
+      Native.expand(Jason.encode!(Map.put(body, "@context", contexts)), [])
+    end
+
+    test "a later context can't redefine any term of a protected context" do
+      for term <- ["name", "knows"] do
+        assert {:error, {:protected_term_redefinition, _}} =
+                 expand_with_contexts([@protected_context, %{term => "http://other.org/#{term}"}], %{"name" => "A"})
+      end
+
+      assert {:error, {:protected_term_redefinition, _}} =
+               Native.expand(Jason.encode!(%{"@context" => @protected_context, "knows" => %{"@context" => %{"name" => "http://other.org/name"}, "name" => "B"}}), [])
+    end
+
+    test "an identical redefinition, a term opting out and a property-scoped context are allowed" do
+      assert {:ok, _} = expand_with_contexts([@protected_context, %{"name" => "http://schema.org/name"}], %{"name" => "A"})
+
+      opted_out = Map.put(@protected_context, "name", %{"@id" => "http://schema.org/name", "@protected" => false})
+      assert {:ok, json} = expand_with_contexts([opted_out, %{"name" => "http://other.org/name"}], %{"name" => "A"})
+      assert [%{"http://other.org/name" => _}] = Jason.decode!(json)
+
+      scoped = Map.put(@protected_context, "member", %{"@id" => "http://schema.org/member", "@context" => %{"name" => "http://other.org/name"}})
+      assert {:ok, json} = expand_with_contexts(scoped, %{"member" => %{"name" => "M"}})
+      assert [%{"http://schema.org/member" => %{"http://other.org/name" => _}}] = Jason.decode!(json)
+    end
+
+    test "protected terms can't be cleared with a null context" do
+      assert {:error, {:invalid_context_nullification, _}} = expand_with_contexts([@protected_context, nil], %{"name" => "A"})
+      assert {:error, {:invalid_protected_value, _}} = expand_with_contexts(%{"@protected" => "yes"}, %{})
+    end
+  end
+
+  describe "diff_structural annotate" do
+    @annotate_old %{"name" => "John", "city" => "NYC", "items" => ["a", "b", "c"]}
+    @annotate_new %{"name" => "Jane", "age" => 30, "items" => ["b", "c", "a"]}
+
+    defp annotated_diff(old, new, opts \\ []) do
+      {:ok, json} = Native.diff_structural(Jason.encode!(old), Jason.encode!(new), [{"annotate", "true"} | opts])
+      Jason.decode!(json)
+    end
+
+    test "each leaf delta is tagged with its kind of change" do
+      diff = annotated_diff(@annotate_old, @annotate_new)
+
+      assert diff["age"] == %{"op" => "added", "delta" => [30]}
+      assert diff["city"] == %{"op" => "removed", "delta" => ["NYC", 0, 0]}
+      assert diff["name"] == %{"op" => "changed", "delta" => ["John", "Jane"]}
+
+      moves = for {key, delta} <- diff["items"], key != "_t", do: delta
+      assert moves != []
+      assert Enum.all?(moves, &match?(%{"op" => "moved", "delta" => ["", _, 3]}, &1))
+    end
+
+    test "text diffs are tagged and the raw format stays the default" do
+      old = %{"bio" => "a fairly long biography"}
+      new = %{"bio" => "a fairly long biographer"}
+
+      assert %{"bio" => %{"op" => "text", "delta" => [_, 0, 2]}} = annotated_diff(old, new, [{"text_diff_threshold", "10"}])
+
+      {:ok, raw} = Native.diff_structural(Jason.encode!(old), Jason.encode!(new), [{"text_diff_threshold", "10"}])
+      assert %{"bio" => [_, 0, 2]} = Jason.decode!(raw)
+    end
+
+    test "patch_structural applies annotated deltas" do
+      old = %{"name" => "John", "city" => "NYC", "op" => %{"delta" => 1}}
+      new = %{"name" => "Jane", "age" => 30, "op" => %{"delta" => 2}}
+      diff = annotated_diff(old, new)
+
+      assert {:ok, patched} = Native.patch_structural(Jason.encode!(old), Jason.encode!(diff), [{"strict", "true"}])
+      assert Jason.decode!(patched) == new
+    end
+  end
+
+  describe "from_rdf use_native_types and use_rdf_type" do
+    @typed_nquads """
+    <http://example.org/s> <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <http://example.org/Thing> .
+    <http://example.org/s> <http://example.org/flag> "true"^^<http://www.w3.org/2001/XMLSchema#boolean> .
+    <http://example.org/s> <http://example.org/count> "42"^^<http://www.w3.org/2001/XMLSchema#integer> .
+    <http://example.org/s> <http://example.org/ratio> "1.5E0"^^<http://www.w3.org/2001/XMLSchema#double> .
+    <http://example.org/s> <http://example.org/odd> "maybe"^^<http://www.w3.org/2001/XMLSchema#boolean> .
+    """
+
+    defp from_rdf_node(opts) do
+      {:ok, json} = Native.from_rdf(@typed_nquads, opts)
+      [node] = Jason.decode!(json)
+      node
+    end
+
+    test "literals keep their datatype by default" do
+      node = from_rdf_node([])
+
+      assert node["http://example.org/count"] == [%{"@value" => "42", "@type" => "http://www.w3.org/2001/XMLSchema#integer"}]
+      assert node["@type"] == ["http://example.org/Thing"]
+      refute Map.has_key?(node, "http://www.w3.org/1999/02/22-rdf-syntax-ns#type")
+    end
+
+    test "use_native_types turns booleans, integers and doubles into JSON scalars" do
+      node = from_rdf_node([{"use_native_types", "true"}])
+
+      assert node["http://example.org/flag"] == [%{"@value" => true}]
+      assert node["http://example.org/count"] == [%{"@value" => 42}]
+      assert node["http://example.org/ratio"] == [%{"@value" => 1.5}]
+      assert node["http://example.org/odd"] == [%{"@value" => "maybe", "@type" => "http://www.w3.org/2001/XMLSchema#boolean"}]
+    end
+
+    test "use_rdf_type keeps rdf:type as a property" do
+      node = from_rdf_node([{"use_rdf_type", "true"}])
+
+      assert node["http://www.w3.org/1999/02/22-rdf-syntax-ns#type"] == [%{"@id" => "http://example.org/Thing"}]
+      refute Map.has_key?(node, "@type")
+    end
+  end
+
+  describe "deref_context" do
+    @people_iri "https://contexts.example.com/deref-people.jsonld"
+    @places_iri "https://contexts.example.com/deref-places.jsonld"
+    @deref_contexts [
+      {@people_iri, Jason.encode!(%{"@context" => %{"name" => "http://schema.org/name", "knows" => "http://schema.org/knows"}})},
+      {@places_iri, Jason.encode!(%{"@context" => %{"@import" => "https://contexts.example.com/deref-vocab.jsonld", "city" => "http://schema.org/addressLocality"}})},
+      {"https://contexts.example.com/deref-vocab.jsonld", Jason.encode!(%{"@vocab" => "http://example.org/vocab#"})}
+    ]
+
+    test "inlines both remote contexts and their imports" do
+      doc = %{"@context" => [@people_iri, @places_iri], "name" => "Alice", "city" => "Paris", "age" => 30}
+
+      assert {:ok, json} = Native.deref_context(Jason.encode!(doc), @deref_contexts)
+      inlined = Jason.decode!(json)
+
+      assert inlined["@context"] == [
+               %{"name" => "http://schema.org/name", "knows" => "http://schema.org/knows"},
+               %{"@vocab" => "http://example.org/vocab#", "city" => "http://schema.org/addressLocality"}
+             ]
+
+      assert {:ok, expanded} = Native.expand(json, [])
+      [node] = Jason.decode!(expanded)
+      assert node["http://schema.org/name"] == %{"@value" => "Alice"}
+      assert node["http://schema.org/addressLocality"] == %{"@value" => "Paris"}
+      assert node["http://example.org/vocab#age"] == %{"@value" => 30}
+    end
+
+    test "a reference missing from the supplied contexts is an error" do
+      doc = %{"@context" => [@people_iri, "https://contexts.example.com/deref-missing.jsonld"], "name" => "Alice"}
+
+      assert {:error, {:loading_remote_context_failed, message}} = Native.deref_context(Jason.encode!(doc), @deref_contexts)
+      assert message =~ "deref-missing.jsonld"
+    end
+  end
+
+  describe "diff_semantic named graphs" do
+    defp graph_doc(name) do
+      %{
+        "@graph" => [
+          %{"@id" => "http://example.com/a", "http://schema.org/name" => "A"},
+          %{
+            "@id" => "http://example.com/g1",
+            "@graph" => [%{"@id" => "http://example.com/b", "http://schema.org/name" => name}]
+          }
+        ]
+      }
+    end
+
+    test "a change inside a named graph is attributed to that graph" do
+      {:ok, json} = Native.diff_semantic(Jason.encode!(graph_doc("B")), Jason.encode!(graph_doc("B2")), [])
+      diff = Jason.decode!(json)
+
+      assert [%{"graph" => "http://example.com/g1", "subject" => "http://example.com/b"}] = diff["added_triples"]
+      assert [%{"graph" => "http://example.com/g1", "subject" => "http://example.com/b"}] = diff["removed_triples"]
+
+      assert [%{"node_id" => "http://example.com/b", "graph" => "http://example.com/g1", "modified_properties" => [_]}] =
+               diff["modified_nodes"]
+
+      assert diff["modified_graphs"] == %{"http://example.com/g1" => ["http://example.com/b"]}
+    end
+
+    test "N-Quads output names the graph" do
+      {:ok, json} = Native.diff_semantic(Jason.encode!(graph_doc("B")), Jason.encode!(graph_doc("B2")), [{"output", "nquads"}])
+
+      assert [line] = Jason.decode!(json)["added_triples"]
+      assert line == ~s(<http://example.com/b> <http://schema.org/name> "B2" <http://example.com/g1> .)
+    end
+  end
+
+  describe "expand_nodes" do
+    test "returns each top-level node as its own JSON string" do
+      doc = %{
+        "@context" => %{"@vocab" => "http://schema.org/"},
+        "@graph" => [
+          %{"@id" => "http://example.org/a", "name" => "A"},
+          %{"@id" => "http://example.org/b", "name" => "B"},
+          %{"@id" => "http://example.org/c", "name" => "C"}
+        ]
+      }
+
+      assert {:ok, nodes} = Native.expand_nodes(Jason.encode!(doc), [])
+      assert length(nodes) == 3
+      assert Enum.all?(nodes, &is_binary/1)
+
+      assert Enum.map(nodes, &Jason.decode!/1) == [
+               %{"@id" => "http://example.org/a", "http://schema.org/name" => %{"@value" => "A"}},
+               %{"@id" => "http://example.org/b", "http://schema.org/name" => %{"@value" => "B"}},
+               %{"@id" => "http://example.org/c", "http://schema.org/name" => %{"@value" => "C"}}
+             ]
+
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+      assert Enum.map(nodes, &Jason.decode!/1) == Jason.decode!(json)
+    end
+
+    test "expansion errors are returned as they are by expand" do
+      doc = %{"@context" => %{"@version" => 2.0}, "name" => "A"}
+      assert {:error, {:invalid_version_value, _}} = Native.expand_nodes(Jason.encode!(doc), [])
+      assert {:error, _} = Native.expand_nodes("{", [])
+    end
+  end
+
+  describe "diff_operational parent_hash" do
+    test "metadata records the parent hash and the hash of the new document" do
+      old = %{"@id" => "http://example.org/a", "name" => "Alice"}
+      new = %{"name" => "Alicia", "@id" => "http://example.org/a"}
+      {:ok, parent} = Native.hash_document(Jason.encode!(old))
+
+      {:ok, json} = Native.diff_operational(Jason.encode!(old), Jason.encode!(new), [{"parent_hash", parent}])
+      metadata = Jason.decode!(json)["metadata"]
+
+      assert metadata["parent"] == parent
+      assert {:ok, metadata["result"]} == Native.hash_document(Jason.encode!(new))
+      assert metadata["result"] == Base.encode16(:crypto.hash(:sha256, ~s({"@id":"http://example.org/a","name":"Alicia"})), case: :lower)
+    end
+
+    test "hashes ignore key order and formatting, and are left out without parent_hash" do
+      assert Native.hash_document(~s({"b": 1, "a": [true, null]})) == Native.hash_document(~s({"a":[true,null],"b":1}))
+
+      {:ok, json} = Native.diff_operational(~s({"a": 1}), ~s({"a": 2}), [])
+      refute Map.has_key?(Jason.decode!(json)["metadata"], "parent")
+      refute Map.has_key?(Jason.decode!(json)["metadata"], "result")
+    end
+  end
+
+  describe "compact @nest" do
+    @nest_context %{
+      "@vocab" => "http://schema.org/",
+      "address" => "@nest",
+      "street" => %{"@id" => "http://schema.org/streetAddress", "@nest" => "address"},
+      "city" => %{"@id" => "http://schema.org/addressLocality", "@nest" => "address"}
+    }
+
+    test "nested properties are grouped back under their nesting key" do
+      doc = %{
+        "@context" => @nest_context,
+        "@id" => "http://example.org/a",
+        "name" => "Acme",
+        "address" => %{"street" => "Main St", "city" => "Springfield"}
+      }
+
+      {:ok, expanded} = Native.expand(Jason.encode!(doc), [])
+      [node] = Jason.decode!(expanded)
+      assert node["http://schema.org/streetAddress"] == %{"@value" => "Main St"}
+
+      {:ok, json} = Native.compact(expanded, Jason.encode!(@nest_context), [{"unmapped", "vocab_relative"}])
+      compacted = Jason.decode!(json)
+
+      assert compacted["address"] == %{"street" => "Main St", "city" => "Springfield"}
+      assert compacted["name"] == "Acme"
+      refute Map.has_key?(compacted, "street")
+      assert Map.delete(compacted, "@context") == Map.delete(doc, "@context")
+    end
+
+    test "a nesting key that a property also compacts to is an error" do
+      context = %{
+        "address" => "http://schema.org/address",
+        "street" => %{"@id" => "http://schema.org/streetAddress", "@nest" => "address"}
+      }
+
+      expanded = [%{"http://schema.org/address" => [%{"@value" => "HQ"}], "http://schema.org/streetAddress" => [%{"@value" => "Main St"}]}]
+
+      assert {:error, {:invalid_nest_value, _}} = Native.compact(Jason.encode!(expanded), Jason.encode!(context), [])
+    end
+  end
+
+  describe "to_rdf produce_generalized_rdf" do
+    @generalized_doc Jason.encode!(%{
+                       "@context" => %{"knows" => "_:knows", "items" => %{"@id" => "http://example.org/items", "@container" => "@list"}},
+                       "@id" => "http://example.org/a",
+                       "knows" => "Bob",
+                       "items" => ["x", "y"]
+                     })
+
+    test "blank node predicate triples are dropped by default" do
+      {:ok, nquads} = Native.to_rdf(@generalized_doc, [])
+      refute nquads =~ "\"Bob\""
+      assert nquads =~ "<http://example.org/items>"
+    end
+
+    test "the flag keeps blank node predicate triples" do
+      {:ok, nquads} = Native.to_rdf(@generalized_doc, [{"produce_generalized_rdf", "true"}])
+      assert [_] = Regex.scan(~r/^<http:\/\/example.org\/a> _:b\d+ "Bob" \.$/m, nquads)
+    end
+
+    test "the triple linking a list comes before the list's own triples" do
+      {:ok, nquads} = Native.to_rdf(@generalized_doc, [])
+      lines = String.split(nquads, "\n", trim: true)
+      head = Enum.find_index(lines, &String.starts_with?(&1, "<http://example.org/a> <http://example.org/items> _:"))
+      first = Enum.find_index(lines, &(&1 =~ "rdf-syntax-ns#first"))
+      assert head < first
+    end
+  end
+
+  describe "@type keyword definition" do
+    test "@container @set forces @type to be an array" do
+      doc = %{
+        "@context" => %{"@vocab" => "http://schema.org/", "@type" => %{"@container" => "@set"}},
+        "@id" => "http://example.org/a",
+        "@type" => "Person"
+      }
+
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+      assert [%{"@type" => ["http://schema.org/Person"]}] = Jason.decode!(json)
+
+      {:ok, compacted} = Native.compact(json, Jason.encode!(doc["@context"]), [])
+      assert Jason.decode!(compacted)["@type"] == ["Person"]
+    end
+
+    test "the scoped context applies to typed nodes" do
+      doc = %{
+        "@context" => %{
+          "@vocab" => "http://schema.org/",
+          "@type" => %{"@context" => %{"name" => "http://example.org/typedName"}}
+        },
+        "@type" => "Person",
+        "name" => "Alice"
+      }
+
+      {:ok, json} = Native.expand(Jason.encode!(doc), [])
+      assert [%{"http://example.org/typedName" => _} = node] = Jason.decode!(json)
+      refute Map.has_key?(node, "http://schema.org/name")
+    end
+
+    test "other @type definitions are rejected" do
+      doc = %{"@context" => %{"@type" => %{"@container" => "@list"}}, "@type" => "http://example.org/T"}
+      assert {:error, {:keyword_redefinition, _}} = Native.expand(Jason.encode!(doc), [])
+    end
+  end
+
+  describe "CBOR-LD" do
+    @credential Jason.encode!(%{
+                  "@context" => %{
+                    "@vocab" => "https://www.w3.org/2018/credentials#",
+                    "name" => "https://schema.org/name",
+                    "issuanceDate" => %{"@type" => "http://www.w3.org/2001/XMLSchema#dateTime"}
+                  },
+                  "@id" => "urn:uuid:3978344f-8596-4c3a-a978-8fcaba3903c5",
+                  "@type" => "VerifiableCredential",
+                  "issuer" => %{"@id" => "did:example:issuer"},
+                  "issuanceDate" => "2024-01-01T00:00:00Z",
+                  "credentialSubject" => %{"@id" => "did:example:alice", "name" => "Alice", "age" => 30}
+                })
+
+    test "a credential round-trips through CBOR-LD" do
+      {:ok, expanded} = Native.expand(@credential, [])
+      {:ok, cbor} = Native.jsonld_to_cbor_ld(@credential, [])
+      assert is_binary(cbor)
+      assert byte_size(cbor) < byte_size(expanded)
+
+      {:ok, json} = Native.cbor_ld_to_jsonld(cbor, [])
+      assert Jason.decode!(json) == Jason.decode!(expanded)
+    end
+
+    test "uncompressed payloads and supplied dictionaries round-trip" do
+      {:ok, expanded} = Native.expand(@credential, [])
+      dictionary = Jason.encode!(%{"https://schema.org/name" => 200, "did:example:alice" => 201})
+
+      {:ok, plain} = Native.jsonld_to_cbor_ld(@credential, [{"compression", "false"}])
+      {:ok, compressed} = Native.jsonld_to_cbor_ld(@credential, [{"dictionary", dictionary}])
+      assert byte_size(compressed) < byte_size(plain)
+
+      for {cbor, opts} <- [{plain, []}, {compressed, [{"dictionary", dictionary}]}] do
+        {:ok, json} = Native.cbor_ld_to_jsonld(cbor, opts)
+        assert Jason.decode!(json) == Jason.decode!(expanded)
+      end
+
+      assert {:error, message} = Native.cbor_ld_to_jsonld(compressed, [])
+      assert message =~ "not in the CBOR-LD dictionary"
+    end
+
+    test "dictionary codes below 100 are reserved" do
+      assert {:error, _} = Native.jsonld_to_cbor_ld(@credential, [{"dictionary", ~s({"https://schema.org/name": 5})}])
+    end
+
+    test "deeply nested payloads are an error rather than a stack overflow" do
+      # The uncompressed payload tag, 0x4A4C4430
+      tag = <<0xDA, "JLD0">>
+      nested = tag <> :binary.copy(<<0x81>>, 2_000_000) <> <<0xF6>>
+
+      assert {:error, message} = Native.cbor_ld_to_jsonld(nested, [])
+      assert message =~ "CBOR nesting deeper than 512"
+
+      shallow = tag <> :binary.copy(<<0x81>>, 10) <> <<0xF6>>
+      assert {:ok, _} = Native.cbor_ld_to_jsonld(shallow, [])
+      assert {:error, _} = Native.cbor_ld_to_jsonld(shallow, [{"max_depth", "5"}])
+    end
+  end
+
+  describe "max_blank_nodes" do
+    @nested_anonymous Jason.encode!(
+                        Enum.reduce(1..50, %{"http://example.org/value" => "leaf"}, fn _, inner ->
+                          %{"http://example.org/child" => inner}
+                        end)
+                      )
+
+    test "a document minting more blank nodes than the cap fails cleanly" do
+      for fun <- [&Native.expand/2, &Native.to_rdf/2, &Native.flatten(&1, nil, &2)] do
+        assert {:error, {:blank_node_limit_exceeded, message}} = fun.(@nested_anonymous, [{"max_blank_nodes", "10"}])
+        assert message =~ "more than 10 blank nodes"
+      end
+    end
+
+    test "documents within the cap are unaffected" do
+      assert {:ok, _} = Native.expand(@nested_anonymous, [{"max_blank_nodes", "51"}])
+      assert {:ok, _} = Native.expand(@nested_anonymous, [])
+    end
+
+    test "a blank node label counts once however often it is referenced" do
+      doc = Jason.encode!(%{"@id" => "_:a", "http://example.org/self" => [%{"@id" => "_:a"}, %{"@id" => "_:a"}]})
+      assert {:ok, _} = Native.expand(doc, [{"max_blank_nodes", "1"}])
+    end
+
+    test "expansion stops at the node that crosses the cap" do
+      items = for i <- 1..1000, do: %{"http://example.org/n" => i}
+      doc = Jason.encode!(%{"@id" => "http://example.org/a", "http://example.org/items" => items})
+
+      assert {:error, {:blank_node_limit_exceeded, message}} = Native.expand(doc, [{"max_blank_nodes", "10"}])
+      assert message =~ "at /http:~1~1example.org~1items/10"
+    end
+
+    test "an @id map entry takes its label from the key" do
+      doc = Jason.encode!(%{
+        "@context" => %{"byId" => %{"@id" => "http://example.org/byId", "@container" => "@id"}},
+        "byId" => %{"http://example.org/x" => %{"http://example.org/p" => "v"}, "_:y" => %{"http://example.org/p" => "w"}}
+      })
+
+      assert {:ok, _} = Native.expand(doc, [{"max_blank_nodes", "1"}])
+      assert {:error, {:blank_node_limit_exceeded, _}} = Native.expand(doc, [{"max_blank_nodes", "0"}])
+    end
+  end
+
+  describe "preserve_order" do
+    @ordered ~s({"@context": {"@vocab": "http://schema.org/", "id": "@id"}, "name": "Widget", "id": "http://example.org/w", "sku": "W-1", "color": "red", "@type": "Product"})
+
+    test "records the authored key order of a multi-property node" do
+      assert {:ok, json, %{"property_order" => [order]}} = Native.expand(@ordered, [{"preserve_order", "true"}])
+
+      assert order == [
+               "http://schema.org/name",
+               "@id",
+               "http://schema.org/sku",
+               "http://schema.org/color",
+               "@type"
+             ]
+
+      [node] = Jason.decode!(json)
+      assert Enum.sort(order) == Enum.sort(Map.keys(node))
+    end
+
+    test "orders line up with the top-level nodes" do
+      doc = ~s([{"http://example.org/b": 1, "@id": "http://example.org/1"}, {"@id": "http://example.org/2", "http://example.org/z": 1, "http://example.org/a": 2}])
+
+      for fun <- [&Native.expand/2, &Native.expand_binary/2] do
+        assert {:ok, _, %{"property_order" => orders}} = fun.(doc, [{"preserve_order", "true"}])
+
+        assert orders == [
+                 ["http://example.org/b", "@id"],
+                 ["@id", "http://example.org/z", "http://example.org/a"]
+               ]
+      end
+    end
+
+    test "without the option the result has no metadata" do
+      assert {:ok, _} = Native.expand(@ordered, [])
+    end
+  end
+
+  describe "canonicalize_nquads" do
+    test "isomorphic N-Quads inputs canonicalize to identical output" do
+      a = """
+      _:x <http://example.org/knows> _:y .
+      _:y <http://example.org/name> "Bob"@en <http://example.org/graph> .
+      <http://example.org/alice> <http://example.org/friend> _:x .
+      """
+
+      b = """
+      <http://example.org/alice> <http://example.org/friend> _:first .
+      _:second <http://example.org/name> "Bob"@en <http://example.org/graph> .
+      _:first <http://example.org/knows> _:second .
+      """
+
+      {:ok, canonical_a} = Native.canonicalize_nquads(a, [])
+      {:ok, canonical_b} = Native.canonicalize_nquads(b, [])
+      assert canonical_a == canonical_b
+      assert canonical_a =~ "_:c14n0"
+      refute canonical_a =~ "_:x"
+    end
+
+    test "bnode_map maps input labels to canonical ones" do
+      {:ok, nquads, map} = Native.canonicalize_nquads("_:x <http://example.org/p> \"v\" .\n", [{"bnode_map", "true"}])
+      assert Jason.decode!(map) == %{"_:x" => "_:c14n0"}
+      assert nquads == "_:c14n0 <http://example.org/p> \"v\" .\n"
+    end
+
+    test "malformed N-Quads are rejected" do
+      assert {:error, {:invalid_nquads, _}} = Native.canonicalize_nquads("<http://example.org/a> <http://example.org/b> .\n", [])
+    end
+  end
+
+  describe "patch_semantic term renames" do
+    test "a renamed term is rewritten in the body as well as the context" do
+      old = ~s({"@context": {"name": "http://schema.org/name"}, "@id": "http://example.org/w", "name": "Widget", "http://example.org/part": {"name": "Bolt"}})
+
+      patch =
+        Jason.encode!(%{
+          "context_changes" => %{
+            "added_mappings" => %{"label" => "http://schema.org/name"},
+            "removed_mappings" => %{"name" => "http://schema.org/name"},
+            "changed_mappings" => %{}
+          }
+        })
+
+      assert {:ok, patched} = Native.patch_semantic(old, patch, [])
+
+      patched = Jason.decode!(patched)
+      assert patched["@context"] == %{"label" => "http://schema.org/name"}
+      assert patched["label"] == "Widget"
+      assert patched["http://example.org/part"] == %{"label" => "Bolt"}
+      refute Map.has_key?(patched, "name")
+    end
+
+    test "an ambiguous rename leaves the body alone" do
+      doc = ~s({"@context": {"name": "http://schema.org/name"}, "name": "Widget"})
+
+      patch =
+        Jason.encode!(%{
+          "context_changes" => %{
+            "added_mappings" => %{"a" => "http://schema.org/name", "b" => "http://schema.org/name"},
+            "removed_mappings" => %{"name" => "http://schema.org/name"}
+          }
+        })
+
+      assert {:ok, patched} = Native.patch_semantic(doc, patch, [])
+      assert %{"name" => "Widget"} = Jason.decode!(patched)
+    end
+
+    test "a renamed term is rewritten where @type uses it" do
+      doc = ~s({"@context": {"Person": "http://schema.org/Person"}, "@id": "http://example.org/w", "@type": "Person"})
+
+      patch =
+        Jason.encode!(%{
+          "context_changes" => %{
+            "added_mappings" => %{"Human" => "http://schema.org/Person"},
+            "removed_mappings" => %{"Person" => "http://schema.org/Person"}
+          }
+        })
+
+      assert {:ok, patched} = Native.patch_semantic(doc, patch, [])
+      assert %{"@type" => "Human"} = Jason.decode!(patched)
+    end
+  end
+
+  describe "expand language maps" do
+    @language_context %{
+      "label" => %{"@id" => "http://example.org/label", "@container" => "@language"},
+      "none" => "@none"
+    }
+
+    defp expanded_labels(labels) do
+      doc = Jason.encode!(%{"@context" => @language_context, "@id" => "http://example.org/a", "label" => labels})
+      {:ok, json} = Native.expand(doc, [])
+      [node] = Jason.decode!(json)
+      node |> Map.get("http://example.org/label", []) |> List.wrap()
+    end
+
+    test "each element of an array-valued entry gets the key's language" do
+      labels = expanded_labels(%{"en" => ["Hi", "Hello"], "de" => "Hallo"})
+
+      assert Enum.sort_by(labels, & &1["@value"]) == [
+               %{"@value" => "Hallo", "@language" => "de"},
+               %{"@value" => "Hello", "@language" => "en"},
+               %{"@value" => "Hi", "@language" => "en"}
+             ]
+    end
+
+    test "@none and its alias give untagged values" do
+      labels = expanded_labels(%{"@none" => ["plain", "text"], "none" => "aliased"})
+
+      assert Enum.sort_by(labels, & &1["@value"]) == [
+               %{"@value" => "aliased"},
+               %{"@value" => "plain"},
+               %{"@value" => "text"}
+             ]
+    end
+
+    test "null entries and array elements are dropped" do
+      labels = expanded_labels(%{"en" => ["Hi", nil], "de" => nil, "@none" => [nil]})
+      assert labels == [%{"@value" => "Hi", "@language" => "en"}]
+    end
+  end
+
+  describe "batch_process canonicalize and hash" do
+    @batch_doc ~s({"@context": {"@vocab": "http://schema.org/"}, "name": "Widget", "manufacturer": {"name": "Acme"}})
+
+    test "canonicalizes and hashes documents in one call" do
+      args = Jason.encode!(%{"document" => @batch_doc, "opts" => %{}})
+
+      assert {:ok, [{:ok, nquads}, {:ok, digest}]} = Native.batch_process([{"canonicalize", args}, {"hash", args}])
+      assert {:ok, ^nquads} = Native.normalize_rdf_graph(@batch_doc, "rdfc-1.0")
+      assert digest == :crypto.hash(:sha256, nquads) |> Base.encode16(case: :lower)
+    end
+
+    test "documents can be given as JSON instead of a string" do
+      inline = Jason.encode!(%{"document" => Jason.decode!(@batch_doc)})
+      quoted = Jason.encode!(%{"document" => @batch_doc})
+
+      assert {:ok, [{:ok, digest}, {:ok, digest}]} = Native.batch_process([{"hash", inline}, {"hash", quoted}])
+    end
+
+    test "failures are reported per operation" do
+      unsupported = Jason.encode!(%{"document" => @batch_doc, "opts" => %{"algorithm" => "md5"}})
+
+      assert {:ok, [unsupported, invalid, {:ok, _nquads}]} =
+               Native.batch_process([
+                 {"hash", unsupported},
+                 {"canonicalize", "[]"},
+                 {"canonicalize", Jason.encode!(%{"document" => @batch_doc})}
+               ])
+
+      assert {:error, {:unsupported_algorithm, message}} = unsupported
+      assert message =~ "md5"
+      assert {:error, {:invalid_input, _}} = invalid
+    end
+
+    test "expand results stay inline JSON alongside tagged ones" do
+      args = Jason.encode!(%{"document" => @batch_doc})
+
+      assert {:ok, [expanded, {:ok, _digest}]} = Native.batch_process([{"expand", @batch_doc}, {"hash", args}])
+      assert [%{"http://schema.org/name" => _}] = Jason.decode!(expanded)
+    end
+  end
+
+  describe "diff_operational max_operations" do
+    @wide_old Jason.encode!(Map.new(1..20, &{"field#{&1}", &1}))
+    @wide_new Jason.encode!(Map.new(1..20, &{"field#{&1}", &1 * 10}))
+
+    test "a change over the cap becomes one root-level set of the new document" do
+      {:ok, diff} = Native.diff_operational(@wide_old, @wide_new, [{"max_operations", "5"}])
+      diff = Jason.decode!(diff)
+
+      assert [%{"type" => "set", "path" => [], "value" => value}] = diff["operations"]
+      assert value == Jason.decode!(@wide_new)
+      assert diff["metadata"]["replaced"] == %{"operations" => 20}
+
+      {:ok, patched} = Native.patch_operational(@wide_old, Jason.encode!(diff), [])
+      assert Jason.decode!(patched) == Jason.decode!(@wide_new)
+    end
+
+    test "changes within the cap keep their operations" do
+      {:ok, diff} = Native.diff_operational(@wide_old, @wide_new, [{"max_operations", "20"}])
+      diff = Jason.decode!(diff)
+
+      assert length(diff["operations"]) == 20
+      refute Map.has_key?(diff["metadata"], "replaced")
+    end
+  end
+
+  describe "expand node-level @version" do
+    @misplaced_version ~s({"@context": {"@version": 1.1, "@vocab": "http://example.org/"}, "@version": 1.1, "@id": "http://example.org/a", "name": "A"})
+
+    test "is removed from the expanded node with a warning" do
+      assert {:ok, json, metadata} = Native.expand(@misplaced_version, [{"collect_warnings", "true"}])
+
+      assert [node] = Jason.decode!(json)
+      refute Map.has_key?(node, "@version")
+      assert node["http://example.org/name"] == %{"@value" => "A"}
+      assert [%{"code" => "misplaced_keyword", "path" => "/@version"}] = Jason.decode!(metadata)["warnings"]
+    end
+
+    test "fails in strict mode" do
+      assert {:error, {:misplaced_keyword, message}} = Native.expand(@misplaced_version, [{"strict", "true"}])
+      assert message =~ "only allowed in a @context"
+    end
+
+    test "is still honored inside the context" do
+      doc = ~s({"@context": {"@version": 1.1, "@vocab": "http://example.org/"}, "name": "A"})
+      assert {:ok, json, ~s({"warnings":[]})} = Native.expand(doc, [{"collect_warnings", "true"}])
+      assert [%{"http://example.org/name" => _}] = Jason.decode!(json)
+    end
+  end
+
+  describe "normalize_document" do
+    test "equivalent documents normalize to byte-identical JSON" do
+      compacted = ~s({
+        "@context": {"@vocab": "http://schema.org/"},
+        "@id": "http://example.org/w",
+        "@type": ["Product", "Thing"],
+        "name": "Widget",
+        "offers": [{"price": 5}, {"price": 3, "seller": {"@id": "_:shop"}}],
+        "steps": {"@list": ["cut", "polish"]}
+      })
+
+      expanded = ~s([
+        {"http://schema.org/steps": {"@list": ["cut", "polish"]}, "@id": "http://example.org/w",
+         "http://schema.org/offers": [{"http://schema.org/seller": {"@id": "_:store"}, "http://schema.org/price": 3}, {"http://schema.org/price": 5}],
+         "@type": ["http://schema.org/Thing", "http://schema.org/Product"],
+         "http://schema.org/name": "Widget"}
+      ])
+
+      assert {:ok, normalized} = Native.normalize_document(compacted, [])
+      assert {:ok, ^normalized} = Native.normalize_document(expanded, [])
+    end
+
+    test "output is flattened with canonical blank node labels and sorted nodes" do
+      doc = ~s({"@context": {"@vocab": "http://schema.org/"}, "@id": "http://example.org/b", "knows": [{"@id": "http://example.org/a", "name": "A"}, {"name": "Anon"}]})
+
+      assert {:ok, json} = Native.normalize_document(doc, [])
+      assert %{"@graph" => nodes} = Jason.decode!(json)
+      assert Enum.map(nodes, & &1["@id"]) == ["_:c14n0", "http://example.org/a", "http://example.org/b"]
+    end
+
+    test "expansion errors are returned" do
+      assert {:error, {:misplaced_keyword, _}} = Native.normalize_document(~s({"@version": 1.1, "http://example.org/p": 1}), [{"strict", "true"}])
+    end
+  end
+
+  describe "to_rdf @reverse" do
+    test "an explicit @reverse property becomes a triple with subject and object swapped" do
+      doc = ~s({"@id": "http://example.org/kid", "@reverse": {"http://schema.org/parent": {"@id": "http://example.org/mom"}}})
+
+      assert {:ok, nquads} = Native.to_rdf(doc, [])
+      assert nquads == "<http://example.org/mom> <http://schema.org/parent> <http://example.org/kid> .\n"
+    end
+
+    test "a reverse term expands into @reverse and converts the same way" do
+      doc = ~s({
+        "@context": {"@vocab": "http://schema.org/", "children": {"@reverse": "parent", "@type": "@id"}},
+        "@id": "http://example.org/mom",
+        "children": ["http://example.org/a", "http://example.org/b"]
+      })
+
+      assert {:ok, json} = Native.expand(doc, [])
+      assert [%{"@reverse" => %{"http://schema.org/parent" => [_, _]}}] = Jason.decode!(json)
+
+      assert {:ok, nquads} = Native.to_rdf(doc, [])
+
+      assert nquads |> String.split("\n", trim: true) |> Enum.sort() == [
+               "<http://example.org/a> <http://schema.org/parent> <http://example.org/mom> .",
+               "<http://example.org/b> <http://schema.org/parent> <http://example.org/mom> ."
+             ]
+    end
+
+    test "reverse triples keep their named graph" do
+      doc = ~s({"@id": "http://example.org/g", "@graph": [{"@id": "http://example.org/kid", "@reverse": {"http://schema.org/parent": {"@id": "http://example.org/mom"}}}]})
+
+      assert {:ok, nquads} = Native.to_rdf(doc, [])
+      assert nquads =~ "<http://example.org/mom> <http://schema.org/parent> <http://example.org/kid> <http://example.org/g> ."
+    end
+  end
+end
@@ -16,7 +16,7 @@ defmodule JsonldExTest do
       
       [first_item] = expanded
       assert first_item["@type"] == "http://example.org/Person"
-      assert first_item["http://example.org/name"] == "Jane"
+      assert first_item["http://schema.org/name"] == %{"@value" => "Jane"}
     end
 
     test "expands with schema.org context" do